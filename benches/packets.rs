@@ -0,0 +1,234 @@
+// Packets per second through the packet path, run with `cargo bench --bench packets`.
+//
+// parse:    a datagram copied into `Datagram` against the borrowed `view::Ipv4`, UDP and TCP
+// udp up:   UDP of one flow from the interface to a local socket, over a socketpair (recvmmsg)
+//           and, on Linux, over a packet mode pipe (one read per packet, like a TUN fd)
+// udp echo: the same with every datagram echoed back down the socketpair (sendmmsg)
+// tcp up:   segments of one connection from the interface to a local listener, each acknowledged.
+//           Only UDP takes the borrowed view all the way, TCP is still copied into a `Datagram` since
+//           the replies are packed from the latest segment of the flow
+
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::os::fd::FromRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tun2socks_rust::config::Config;
+use tun2socks_rust::dispatcher::Dispatcher;
+use tun2socks_rust::logging::Logging;
+use tun2socks_rust::protocol::internet::Datagram;
+use tun2socks_rust::protocol::internet::view::Ipv4;
+
+const PACKETS: usize = 200_000;
+const PAYLOAD: usize = 64;
+
+fn main() {
+    parse("udp", &udp_datagram(40000, 9, &[0x55; PAYLOAD]));
+    parse("tcp", &tcp_datagram(40000, 9, 1, 0b11000, &[0x55; PAYLOAD]));
+    udp_up("udp up, socketpair", socketpair());
+    #[cfg(target_os = "linux")]
+    udp_up("udp up, pipe", pipe());
+    udp_echo();
+    tcp_up();
+}
+
+fn parse(protocol: &str, packet: &[u8]) {
+    let iterations = 1_000_000;
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(Datagram::new(black_box(packet)).map(|datagram| datagram.key()).ok());
+    }
+    report(&format!("parse {protocol}, Datagram"), iterations, start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(Ipv4::new(black_box(packet)).and_then(|packet| packet.key()));
+    }
+    report(&format!("parse {protocol}, view"), iterations, start.elapsed());
+}
+
+fn udp_up(name: &str, (interface, mut client): (File, File)) {
+    let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sink.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let port = sink.local_addr().unwrap().port();
+    let (stopper, dispatcher) = spawn(interface);
+
+    let counter = thread::spawn(move || {
+        let mut buf = [0; 1500];
+        let mut received = 0;
+        while sink.recv(&mut buf).is_ok() {
+            received += 1;
+        }
+        received
+    });
+
+    let packet = udp_datagram(40000, port, &[0x55; PAYLOAD]);
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        client.write_all(&packet).unwrap();
+    }
+    let elapsed = start.elapsed();
+    let received = counter.join().unwrap();
+    report(name, PACKETS, elapsed);
+    if received < PACKETS {
+        println!("{:>24}  {} of {PACKETS} reached the socket", "", received);
+    }
+
+    stopper.stop(Duration::from_secs(5)).unwrap();
+    dispatcher.join().unwrap();
+}
+
+fn udp_echo() {
+    let (interface, mut client) = socketpair();
+    let echo = UdpSocket::bind("127.0.0.1:0").unwrap();
+    echo.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let port = echo.local_addr().unwrap().port();
+    let (stopper, dispatcher) = spawn(interface);
+
+    thread::spawn(move || {
+        let mut buf = [0; 1500];
+        while let Ok((n, from)) = echo.recv_from(&mut buf) {
+            echo.send_to(&buf[..n], from).unwrap_or(0);
+        }
+    });
+
+    let mut reader = client.try_clone().unwrap();
+    let counter = thread::spawn(move || {
+        set_read_timeout(&reader, Duration::from_millis(500));
+        let mut buf = [0; 1500];
+        let mut received = 0;
+        loop {
+            match reader.read(&mut buf) {
+                Ok(n) if n > 0 => received += 1,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                _ => break received,
+            }
+        }
+    });
+
+    let packet = udp_datagram(40000, port, &[0x55; PAYLOAD]);
+    let start = Instant::now();
+    for _ in 0..PACKETS {
+        client.write_all(&packet).unwrap();
+    }
+    let received = counter.join().unwrap();
+    // The reader gave up half a second after the last echo
+    let elapsed = start.elapsed().saturating_sub(Duration::from_millis(500));
+    report("udp echo, socketpair", received, elapsed);
+
+    stopper.stop(Duration::from_secs(5)).unwrap();
+    dispatcher.join().unwrap();
+}
+
+fn tcp_up() {
+    let (interface, mut client) = socketpair();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (stopper, dispatcher) = spawn(interface);
+
+    let mut buf = [0; 1500];
+    client.write_all(&tcp_datagram(40000, port, 100, 0b10, &[])).unwrap();
+    assert!(client.read(&mut buf).unwrap() >= 40);
+    let (mut remote, _) = listener.accept().unwrap();
+
+    // The acknowledgements are read and dropped
+    let mut reader = client.try_clone().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 1500];
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    });
+    let counter = thread::spawn(move || {
+        let mut buf = [0; 65536];
+        let mut received = 0;
+        while received < PACKETS * PAYLOAD {
+            match remote.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => received += n,
+            }
+        }
+        received
+    });
+
+    let start = Instant::now();
+    for i in 0..PACKETS {
+        let seq = 101 + (i * PAYLOAD) as u32;
+        client.write_all(&tcp_datagram(40000, port, seq, 0b11000, &[0x55; PAYLOAD])).unwrap();
+    }
+    let received = counter.join().unwrap();
+    report("tcp up, socketpair", received / PAYLOAD, start.elapsed());
+
+    stopper.stop(Duration::from_secs(5)).unwrap();
+    dispatcher.join().unwrap();
+}
+
+fn spawn(interface: File) -> (tun2socks_rust::dispatcher::Stopper, thread::JoinHandle<()>) {
+    let mut dispatcher = Dispatcher::new(interface, Logging::without_file(), &Config::default()).unwrap();
+    let stopper = dispatcher.stopper();
+    (stopper, thread::spawn(move || dispatcher.run().unwrap()))
+}
+
+fn report(name: &str, packets: usize, elapsed: Duration) {
+    let rate = packets as f64 / elapsed.as_secs_f64();
+    println!("{name:>24}  {packets:>8} packets in {elapsed:>10.2?}  {:>8.3} Mpps", rate / 1e6);
+}
+
+fn socketpair() -> (File, File) {
+    let mut fds = [0; 2];
+    let rs = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+    assert_eq!(rs, 0);
+    unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+}
+
+// Every write is one packet for the reader, and the fd is no socket
+#[cfg(target_os = "linux")]
+fn pipe() -> (File, File) {
+    let mut fds = [0; 2];
+    let rs = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_DIRECT) };
+    assert_eq!(rs, 0);
+    unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+}
+
+fn set_read_timeout(file: &File, timeout: Duration) {
+    use std::os::fd::AsRawFd;
+    let tv = libc::timeval { tv_sec: timeout.as_secs() as _, tv_usec: timeout.subsec_micros() as _ };
+    let size = std::mem::size_of::<libc::timeval>() as libc::socklen_t;
+    let rs = unsafe {
+        libc::setsockopt(file.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVTIMEO, (&tv as *const libc::timeval).cast(), size)
+    };
+    assert_eq!(rs, 0);
+}
+
+fn tcp_datagram(src_port: u16, dst_port: u16, seq: u32, flags: u8, data: &[u8]) -> Vec<u8> {
+    let total_length = (20 + 20 + data.len()) as u16;
+    let mut bytes = vec![69, 0, 0, 0, 0, 1, 64, 0, 64, 6, 0, 0, 10, 0, 0, 1, 127, 0, 0, 1];
+    bytes[2..4].copy_from_slice(&total_length.to_be_bytes());
+    let checksum = Datagram::calc_checksum(&bytes);
+    bytes[10..12].copy_from_slice(&checksum);
+    bytes.extend_from_slice(&src_port.to_be_bytes());
+    bytes.extend_from_slice(&dst_port.to_be_bytes());
+    bytes.extend_from_slice(&seq.to_be_bytes());
+    bytes.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 255, 255, 0, 0, 0, 0]);
+    bytes.extend_from_slice(data);
+    bytes
+}
+
+fn udp_datagram(src_port: u16, dst_port: u16, data: &[u8]) -> Vec<u8> {
+    let total_length = (20 + 8 + data.len()) as u16;
+    let mut bytes = vec![69, 0, 0, 0, 0, 1, 64, 0, 64, 17, 0, 0, 10, 0, 0, 1, 127, 0, 0, 1];
+    bytes[2..4].copy_from_slice(&total_length.to_be_bytes());
+    let checksum = Datagram::calc_checksum(&bytes);
+    bytes[10..12].copy_from_slice(&checksum);
+    bytes.extend_from_slice(&src_port.to_be_bytes());
+    bytes.extend_from_slice(&dst_port.to_be_bytes());
+    bytes.extend_from_slice(&((8 + data.len()) as u16).to_be_bytes());
+    bytes.extend_from_slice(&[0, 0]);
+    bytes.extend_from_slice(data);
+    bytes
+}
@@ -0,0 +1,19 @@
+// Regenerate include/tun2socks.h from the exported functions in src/lib.rs and the #[repr(C)] types they use
+fn main() {
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=src/config.rs");
+    println!("cargo:rerun-if-changed=src/logging/mod.rs");
+    println!("cargo:rerun-if-changed=src/stats.rs");
+    println!("cargo:rerun-if-changed=src/device/framing.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{crate_dir}/cbindgen.toml")).unwrap();
+    match cbindgen::generate_with_config(&crate_dir, config) {
+        Ok(bindings) => {
+            bindings.write_to_file(format!("{crate_dir}/include/tun2socks.h"));
+        }
+        // Keep the committed header rather than failing the build
+        Err(err) => println!("cargo:warning=tun2socks.h not generated: {err}"),
+    }
+}
@@ -0,0 +1,41 @@
+#![no_main]
+
+// The parsers return an error for any input they cannot read, they must never panic
+use libfuzzer_sys::fuzz_target;
+
+use tun2socks_rust::device::{vnet, PacketFraming};
+use tun2socks_rust::dns::message;
+use tun2socks_rust::protocol::internet::icmp::Icmp;
+use tun2socks_rust::protocol::internet::tcp::Tcp;
+use tun2socks_rust::protocol::internet::udp::Udp;
+use tun2socks_rust::protocol::internet::view::Ipv4;
+use tun2socks_rust::protocol::internet::{Datagram, PseudoHeader};
+use tun2socks_rust::protocol::socks5::{auth, negotiation, request};
+
+fuzz_target!(|data: &[u8]| {
+    // IP, and the TCP, UDP or ICMP after the header
+    if let Ok(datagram) = Datagram::new(data) {
+        let _ = datagram.key();
+    }
+    if let Some(packet) = Ipv4::new(data) {
+        let _ = (packet.key(), packet.checksums_valid(true), packet.tcp().map(|tcp| tcp.payload()));
+    }
+
+    // The transport headers on their own
+    let pseudo_header = PseudoHeader { src_ip: [10, 0, 0, 1], dst_ip: [10, 0, 0, 2], protocol: 6, length: [0, 0] };
+    let _ = Tcp::new(data, pseudo_header);
+    let _ = Udp::new(data, pseudo_header);
+    let _ = Icmp::new(data);
+
+    // Replies of a SOCKS5 server
+    let _ = negotiation::Reply::new(data);
+    let _ = auth::Reply::new(data);
+    let _ = request::TcpMessage::parse_reply(data);
+
+    let _ = message::parse_response(data);
+
+    for framing in [PacketFraming::Raw, PacketFraming::AfHeader, PacketFraming::TunPi, PacketFraming::VirtioNet] {
+        let _ = framing.unframe(data);
+    }
+    let _ = vnet::Header::parse(data);
+});
@@ -0,0 +1,316 @@
+/* Generated by cbindgen from src/lib.rs, do not edit. */
+
+#ifndef TUN2SOCKS_H
+#define TUN2SOCKS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Result codes of the exported functions, see `tun2socks_last_error` for the details of a failure.
+typedef enum {
+  // Success.
+  TUN2SOCKS_STATUS_OK = 0,
+  // Failure not covered by a more specific code.
+  TUN2SOCKS_STATUS_ERROR = 1,
+  // `tun2socks_stop` without a running instance.
+  TUN2SOCKS_STATUS_NOT_RUNNING = 2,
+  // An instance is already running, only one is allowed at a time.
+  TUN2SOCKS_STATUS_ALREADY_RUNNING = 3,
+  // The instance did not stop in time, it keeps winding down in the background.
+  TUN2SOCKS_STATUS_TIMEOUT = 4,
+  // A required pointer argument was null.
+  TUN2SOCKS_STATUS_NULL_POINTER = 5,
+  // An argument is out of range, e.g. a negative fd or a path that is not UTF-8.
+  TUN2SOCKS_STATUS_INVALID_ARGUMENT = 6,
+  // The log file could not be opened or the interface could not be set up.
+  TUN2SOCKS_STATUS_IO = 7,
+  // tun2socks panicked, the panic was stopped at the library boundary.
+  TUN2SOCKS_STATUS_PANIC = 8,
+} Tun2socksStatus;
+
+typedef enum {
+  // Refuse the new flow: TCP gets a RST, anything else ICMP destination unreachable (administratively prohibited).
+  TUN2SOCKS_OVERFLOW_POLICY_RESET = 0,
+  // Refuse the new flow with ICMP destination unreachable (administratively prohibited).
+  TUN2SOCKS_OVERFLOW_POLICY_UNREACHABLE = 1,
+  // Close the least recently active flow and give its slot to the new one.
+  TUN2SOCKS_OVERFLOW_POLICY_EVICT_LRU = 2,
+} Tun2socksOverflowPolicy;
+
+// Idle timeouts settable with `tun2socks_config_set_timeout`, see `config::Timeouts`.
+typedef enum {
+  TUN2SOCKS_TIMEOUT_TCP_CONNECT = 0,
+  TUN2SOCKS_TIMEOUT_TCP_ESTABLISHED = 1,
+  TUN2SOCKS_TIMEOUT_TCP_HALF_CLOSED = 2,
+  TUN2SOCKS_TIMEOUT_UDP = 3,
+  TUN2SOCKS_TIMEOUT_DNS = 4,
+  TUN2SOCKS_TIMEOUT_ICMP = 5,
+} Tun2socksTimeout;
+
+// Severity of a log line, the values match the Android log priorities.
+typedef enum {
+  // Per-packet details and hexdumps.
+  TUN2SOCKS_LOG_LEVEL_TRACE = 2,
+  TUN2SOCKS_LOG_LEVEL_DEBUG = 3,
+  TUN2SOCKS_LOG_LEVEL_INFO = 4,
+  TUN2SOCKS_LOG_LEVEL_WARN = 5,
+  TUN2SOCKS_LOG_LEVEL_ERROR = 6,
+  // Only as a module level: nothing is logged.
+  TUN2SOCKS_LOG_LEVEL_SILENT = 8,
+} Tun2socksLogLevel;
+
+// Part of tun2socks a log line comes from, each has its own level.
+typedef enum {
+  // The interface: packets read and written, start and stop.
+  TUN2SOCKS_MODULE_TUN = 0,
+  TUN2SOCKS_MODULE_TCP = 1,
+  TUN2SOCKS_MODULE_UDP = 2,
+  TUN2SOCKS_MODULE_DNS = 3,
+  TUN2SOCKS_MODULE_SOCKS5 = 4,
+  // The flow table: overflow, eviction and idle timeouts.
+  TUN2SOCKS_MODULE_POOL = 5,
+  TUN2SOCKS_MODULE_ICMP = 6,
+} Tun2socksModule;
+
+typedef enum {
+  // `{elapsed:?} {level}: {message} key=value ...`
+  TUN2SOCKS_LOG_FORMAT_TEXT = 0,
+  // One JSON object per line.
+  TUN2SOCKS_LOG_FORMAT_JSON = 1,
+} Tun2socksLogFormat;
+
+// What comes in front of each IP packet read from or written to the interface.
+typedef enum {
+  // Bare IP packets: Linux with IFF_NO_PI, Android VpnService, NEPacketTunnelFlow.
+  TUN2SOCKS_PACKET_FRAMING_RAW = 0,
+  // The address family as 4 bytes in network byte order: utun on macOS and iOS.
+  TUN2SOCKS_PACKET_FRAMING_AF_HEADER = 1,
+  // struct tun_pi, 2 bytes of flags and the EtherType: Linux without IFF_NO_PI.
+  TUN2SOCKS_PACKET_FRAMING_TUN_PI = 2,
+  // struct virtio_net_hdr: Linux with IFF_VNET_HDR, see `vnet`.
+  TUN2SOCKS_PACKET_FRAMING_VIRTIO_NET = 3,
+} Tun2socksPacketFraming;
+
+// Runtime settings of one tun2socks instance.
+typedef struct Tun2socksConfig Tun2socksConfig;
+
+// Copy of the counters at one point in time.
+typedef struct {
+  // IP packets read from the interface.
+  uint64_t packets_up;
+  // IP packets written to the interface.
+  uint64_t packets_down;
+  // TCP and UDP payload sent to remotes.
+  uint64_t bytes_up;
+  // TCP and UDP payload received from remotes.
+  uint64_t bytes_down;
+  // Currently open flows per protocol.
+  uint64_t tcp_flows;
+  uint64_t udp_flows;
+  uint64_t icmp_flows;
+  // Upstream connections (TCP) and sessions (UDP) opened, and those that failed to.
+  uint64_t connect_attempts;
+  uint64_t connect_failures;
+  // UDP datagrams to port 53.
+  uint64_t dns_queries;
+  // Flows closed to make room for new ones.
+  uint64_t evictions;
+  // Flows closed by their idle timeout.
+  uint64_t idle_timeouts;
+  // Dropped packets by `DropReason`.
+  uint64_t drops_malformed;
+  uint64_t drops_unsupported_version;
+  uint64_t drops_unsupported_protocol;
+  uint64_t drops_no_flow;
+  uint64_t drops_flow_table_full;
+  uint64_t drops_write_error;
+  uint64_t drops_bad_checksum;
+  uint64_t drops_ttl_exceeded;
+  // New flows whose destination was named by an earlier DNS response, and those that were not.
+  uint64_t dns_cache_hits;
+  uint64_t dns_cache_misses;
+  // Addresses currently in the DNS cache.
+  uint64_t dns_cache_entries;
+} Tun2socksStats;
+
+// Receives every log line: `level` is a `LogLevel`, `tag` and `message` are nul-terminated
+// and only valid during the call. Called from the tun2socks thread, pass NULL to stop forwarding.
+typedef void (*Tun2socksLogCallback)(int level, const char *tag, const char *message);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Run tun2socks on the TUN `fd` until the interface is closed or `tun2socks_stop` is called.
+// Returns a `Status` code. tun2socks owns `fd` once it started and closes it when it stops, when it
+// fails to start `fd` is left open and still the caller's to close.
+//
+// Without a `log_path` lines only go to the callback of `tun2socks_set_log_callback`.
+//
+// # Safety
+// `log_path` must be null or point to a valid nul-terminated string.
+int tun2socks(int fd, const char *log_path);
+
+// Start tun2socks on the TUN `fd` in the background, stop it with `tun2socks_stop`.
+// Returns a `Status` code. On success tun2socks owns `fd` and closes it when it stops, on failure
+// `fd` is left open and still the caller's to close.
+//
+// Without a `log_path` lines only go to the callback of `tun2socks_set_log_callback`.
+//
+// # Safety
+// `log_path` must be null or point to a valid nul-terminated string.
+int tun2socks_start(int fd, const char *log_path);
+
+// Like `tun2socks_start`, with the settings of `config` instead of the defaults when it is not null.
+// The config is copied, it may be freed right after the call.
+//
+// # Safety
+// `log_path` must be null or point to a valid nul-terminated string,
+// `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_start_with_config(int fd,
+                                const char *log_path,
+                                const Tun2socksConfig *config);
+
+// Like `tun2socks_start_with_config` for the `count` queues in `fds` of a Linux TUN device created
+// with IFF_MULTI_QUEUE, each served by its own thread. UDP mappings are only full cone with one queue.
+// The `fds` are owned like the fd of `tun2socks_start`: all of them on success, none on failure.
+//
+// # Safety
+// `fds` must be null or point to `count` fds, `log_path` must be null or point to a valid nul-terminated
+// string, `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_start_queues(const int *fds,
+                           size_t count,
+                           const char *log_path,
+                           const Tun2socksConfig *config);
+
+// Stop the running instance: reset its TCP flows, close upstream sockets and join its thread.
+// Returns a `Status` code.
+int tun2socks_stop(void);
+
+// Copy the traffic counters of the running instance into `stats`. Returns a `Status` code.
+//
+// # Safety
+// `stats` must be null or point to writable memory for a `Tun2socksStats`.
+int tun2socks_stats(Tun2socksStats *stats);
+
+// Every open flow of the running instance as a JSON array, see `FlowInfo::json` for the fields.
+// Returns null on failure, see `tun2socks_last_error`. Release the string with `tun2socks_string_free`.
+char *tun2socks_flows_json(void);
+
+// Release a string returned by tun2socks, null is ignored.
+//
+// # Safety
+// `string` must be null or come from tun2socks, and not be used afterwards.
+void tun2socks_string_free(char *string);
+
+// Describe why the latest call on this thread failed, null if it succeeded.
+// The string stays valid until the next call into tun2socks on the same thread.
+const char *tun2socks_last_error(void);
+
+// Rotate the log file before it grows past `max_size` bytes or once it is `max_age_secs` old,
+// 0 disables either limit. `keep` rotated files are kept, gzip compressed if `gzip` is set.
+// Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_config_set_log_rotation(Tun2socksConfig *config,
+                                      uint64_t max_size,
+                                      uint64_t max_age_secs,
+                                      size_t keep,
+                                      bool gzip);
+
+// Relay TCP flows through the SOCKS5 server at `url`, `socks5://[user[:password]@]host:port`.
+// Null connects directly. Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`, `url` must be null or a nul-terminated string.
+int tun2socks_config_set_proxy(Tun2socksConfig *config,
+                               const char *url);
+
+// Capture the packets passing the interface into the pcapng file at `path`, null captures nothing.
+// `filter` selects packets by their 5-tuple, e.g. "tcp and port 443", null selects all. Packets are
+// cut to `snaplen` bytes, 0 for 65535, and the file is rotated before it grows past `max_size` bytes,
+// 0 for no limit, keeping `keep` rotated files. Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`, `path` and `filter` must be null or
+// nul-terminated strings.
+int tun2socks_config_set_capture(Tun2socksConfig *config,
+                                 const char *path,
+                                 const char *filter,
+                                 uint32_t snaplen,
+                                 uint64_t max_size,
+                                 size_t keep);
+
+// Set the header in front of the packets on the interface, `framing` is a `PacketFraming`.
+// `VirtioNet` is for a Linux TUN device created with IFF_VNET_HDR, enable TSO on it (TUNSETOFFLOAD)
+// to receive TCP super-packets as well. Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_config_set_framing(Tun2socksConfig *config, int framing);
+
+// Drop packets from the interface with a wrong IP, TCP, UDP or ICMP checksum, counted as
+// `drops_bad_checksum`. Off by default. Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_config_set_verify_checksums(Tun2socksConfig *config, bool verify);
+
+// Answer packets whose time to live runs out at the interface with ICMP time exceeded from
+// `address`, the first hop traceroute shows. Null answers from their destination. Returns a
+// `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`, `address` must be null or a nul-terminated string.
+int tun2socks_config_set_gateway(Tun2socksConfig *config,
+                                 const char *address);
+
+// Forward every log line to `callback`, also while no instance is running. Null stops forwarding.
+// The callback runs on the tun2socks thread and must not block for long.
+int tun2socks_set_log_callback(Tun2socksLogCallback callback);
+
+// Log lines of `module` (a `Module`) at `level` (a `LogLevel`) and above, `SILENT` turns it off.
+// Every module starts at `INFO`. Returns a `Status` code.
+int tun2socks_set_log_level(int module, int level);
+
+// Write log lines as text or as JSON objects, `format` is a `LogFormat`. Returns a `Status` code.
+int tun2socks_set_log_format(int format);
+
+// Version of the library, e.g. "0.1.0". The string is static.
+const char *tun2socks_version(void);
+
+// Create a config holding the defaults, release it with `tun2socks_config_free`.
+Tun2socksConfig *tun2socks_config_new(void);
+
+// Release a config, null is ignored.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`, and not be used afterwards.
+void tun2socks_config_free(Tun2socksConfig *config);
+
+// Set the upper bound of concurrently tracked flows. Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_config_set_max_flows(Tun2socksConfig *config, size_t max_flows);
+
+// Set what happens to new flows once the flow table is full, `policy` is an `OverflowPolicy`.
+// Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_config_set_overflow_policy(Tun2socksConfig *config, int policy);
+
+// Set one idle timeout, `timeout` is a `Timeout`. Returns a `Status` code.
+//
+// # Safety
+// `config` must be null or come from `tun2socks_config_new`.
+int tun2socks_config_set_timeout(Tun2socksConfig *config, int timeout, uint64_t millis);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* TUN2SOCKS_H */
@@ -0,0 +1,144 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+
+/// Selects packets by their 5-tuple with a small subset of the tcpdump syntax:
+/// `tcp`, `udp`, `icmp`, `[src|dst] host <ipv4>` and `[src|dst] port <n>`, each optionally
+/// preceded by `not`, joined by `and`, which binds tighter than `or`.
+///
+/// `tcp and dst port 443 or udp and not port 53`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    // Alternatives of conjunctions
+    any: Vec<Vec<Term>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Term {
+    negated: bool,
+    primitive: Primitive,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Primitive {
+    Protocol(u8),
+    Host(Side, Ipv4Addr),
+    Port(Side, u16),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Side {
+    Src,
+    Dst,
+    Either,
+}
+
+// The fields of an IPv4 packet a filter looks at
+struct Tuple {
+    protocol: u8,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    ports: Option<(u16, u16)>,
+}
+
+impl Filter {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidInput, format!("filter({expression}): {message}"));
+        let words: Vec<&str> = expression.split_whitespace().collect();
+        if words.is_empty() {
+            return Err(invalid("empty".to_string()));
+        }
+
+        let mut any = Vec::new();
+        for alternative in words.split(|word| *word == "or") {
+            let mut all = Vec::new();
+            for term in alternative.split(|word| *word == "and") {
+                all.push(Term::parse(term).map_err(invalid)?);
+            }
+            any.push(all);
+        }
+        Ok(Self { any })
+    }
+
+    /// Whether the raw IP `packet` is selected, anything but an IPv4 packet never is.
+    pub fn matches(&self, packet: &[u8]) -> bool {
+        match Tuple::parse(packet) {
+            Some(tuple) => self.any.iter().any(|all| all.iter().all(|term| term.matches(&tuple))),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(expression: &str) -> Result<Self> {
+        Self::parse(expression)
+    }
+}
+
+impl Term {
+    fn parse(words: &[&str]) -> std::result::Result<Self, String> {
+        let (negated, words) = match words {
+            ["not", rest @ ..] => (true, rest),
+            _ => (false, words),
+        };
+        let (side, words) = match words {
+            ["src", rest @ ..] => (Side::Src, rest),
+            ["dst", rest @ ..] => (Side::Dst, rest),
+            _ => (Side::Either, words),
+        };
+        let primitive = match (side, words) {
+            (Side::Either, ["tcp"]) => Primitive::Protocol(TCP),
+            (Side::Either, ["udp"]) => Primitive::Protocol(UDP),
+            (Side::Either, ["icmp"]) => Primitive::Protocol(ICMP),
+            (side, ["host", host]) => Primitive::Host(side, host.parse().map_err(|_| format!("invalid host({host})"))?),
+            (side, ["port", port]) => Primitive::Port(side, port.parse().map_err(|_| format!("invalid port({port})"))?),
+            _ => return Err(format!("unknown term({})", words.join(" "))),
+        };
+        Ok(Self { negated, primitive })
+    }
+
+    fn matches(&self, tuple: &Tuple) -> bool {
+        let either = |side: Side, src: bool, dst: bool| match side {
+            Side::Src => src,
+            Side::Dst => dst,
+            Side::Either => src || dst,
+        };
+        let matched = match self.primitive {
+            Primitive::Protocol(protocol) => tuple.protocol == protocol,
+            Primitive::Host(side, host) => either(side, tuple.src == host, tuple.dst == host),
+            Primitive::Port(side, port) => {
+                tuple.ports.is_some_and(|(src, dst)| either(side, src == port, dst == port))
+            }
+        };
+        matched != self.negated
+    }
+}
+
+impl Tuple {
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 20 || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let ihl = (packet[0] & 0x0F) as usize * 4;
+        let protocol = packet[9];
+        let first_fragment = u16::from_be_bytes([packet[6], packet[7]]) & 0x1FFF == 0;
+        let ports = match (protocol, packet.get(ihl..ihl + 4)) {
+            (TCP | UDP, Some(ports)) if first_fragment => {
+                Some((u16::from_be_bytes([ports[0], ports[1]]), u16::from_be_bytes([ports[2], ports[3]])))
+            }
+            _ => None,
+        };
+        Some(Self {
+            protocol,
+            src: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+            dst: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+            ports,
+        })
+    }
+}
@@ -0,0 +1,78 @@
+use std::io::{Error, Result};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::logging::{Rotation, Sink};
+
+pub use filter::Filter;
+
+mod filter;
+pub mod pcapng;
+
+/// Where and what to capture, see `Config::capture`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The pcapng file, rotated files are `<path>.1` and so on. An existing file is appended to.
+    pub path: String,
+    /// Only capture the packets it matches, `None` captures all.
+    pub filter: Option<Filter>,
+    /// Bytes kept of each packet, the rest is cut off.
+    pub snaplen: u32,
+    pub rotation: Rotation,
+}
+
+impl Options {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            filter: None,
+            snaplen: DEFAULT_SNAPLEN,
+            rotation: Rotation::default(),
+        }
+    }
+}
+
+pub const DEFAULT_SNAPLEN: u32 = 65535;
+
+/// Which way a packet went through the TUN device, each has its own pcapng interface.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Read from the device: clients towards remotes.
+    Up = 0,
+    /// Written to the device: remotes towards clients.
+    Down = 1,
+}
+
+/// Writes the IP packets passing the TUN device into a pcapng file.
+pub struct Capture {
+    sink: Sink,
+    filter: Option<Filter>,
+    snaplen: u32,
+}
+
+impl Capture {
+    pub fn open(options: &Options) -> Result<Self> {
+        let snaplen = options.snaplen.max(1);
+        let mut header = pcapng::section_header();
+        header.extend(pcapng::interface_description("tun-up", "read from the TUN device, clients towards remotes", snaplen));
+        header.extend(pcapng::interface_description("tun-down", "written to the TUN device, remotes towards clients", snaplen));
+        let sink = Sink::with_header(&options.path, options.rotation, header)
+            .map_err(|err| Error::new(err.kind(), format!("open capture {}: {err}", options.path)))?;
+        Ok(Self { sink, filter: options.filter.clone(), snaplen })
+    }
+
+    /// Record the raw IP `packet`, without any platform header, if the filter selects it.
+    pub fn packet(&mut self, direction: Direction, packet: &[u8]) {
+        if self.filter.as_ref().is_some_and(|filter| !filter.matches(packet)) {
+            return;
+        }
+        let captured = &packet[..packet.len().min(self.snaplen as usize)];
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        // epb_flags: inbound for tun2socks when read, outbound when written
+        let flags = match direction {
+            Direction::Up => 1,
+            Direction::Down => 2,
+        };
+        let block = pcapng::enhanced_packet(direction as u32, timestamp, captured, packet.len(), flags);
+        self.sink.write(&[&block]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+// Block types
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Bare IPv4/IPv6 packets without a link layer header.
+pub const LINKTYPE_RAW: u16 = 101;
+
+// Option codes
+const OPT_END: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_DESCRIPTION: u16 = 3;
+const EPB_FLAGS: u16 = 2;
+
+/// Start of a section, every file begins with one. The section length is left unspecified.
+pub fn section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    option(&mut body, SHB_USERAPPL, concat!("tun2socks ", env!("CARGO_PKG_VERSION")).as_bytes());
+    option(&mut body, OPT_END, &[]);
+    block(SECTION_HEADER, &body)
+}
+
+/// Interface with raw IP packets, timestamps in microseconds.
+pub fn interface_description(name: &str, description: &str, snaplen: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&snaplen.to_le_bytes());
+    option(&mut body, IF_NAME, name.as_bytes());
+    option(&mut body, IF_DESCRIPTION, description.as_bytes());
+    option(&mut body, OPT_END, &[]);
+    block(INTERFACE_DESCRIPTION, &body)
+}
+
+/// `data` is the captured part of a packet `original_len` long, `flags` the epb_flags word
+/// (1 inbound, 2 outbound), `timestamp` counts from the Unix epoch.
+pub fn enhanced_packet(interface: u32, timestamp: Duration, data: &[u8], original_len: usize, flags: u32) -> Vec<u8> {
+    let micros = timestamp.as_micros() as u64;
+    let mut body = Vec::with_capacity(data.len() + 40);
+    body.extend_from_slice(&interface.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(original_len as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    pad(&mut body);
+    option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+    option(&mut body, OPT_END, &[]);
+    block(ENHANCED_PACKET, &body)
+}
+
+// Type and total length around `body`, which is a multiple of 4 long
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+fn option(out: &mut Vec<u8>, code: u16, value: &[u8]) {
+    out.extend_from_slice(&code.to_le_bytes());
+    out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    out.extend_from_slice(value);
+    pad(out);
+}
+
+fn pad(out: &mut Vec<u8>) {
+    out.resize(out.len().next_multiple_of(4), 0);
+}
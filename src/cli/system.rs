@@ -0,0 +1,107 @@
+use std::net::{Ipv4Addr, SocketAddr};
+#[cfg(target_os = "linux")]
+use std::net::IpAddr;
+#[cfg(target_os = "linux")]
+use std::process::Command;
+
+use super::System;
+
+/// `a.b.c.d/len`, the address has no bits set beyond the prefix.
+pub fn parse_route(route: &str) -> Result<(Ipv4Addr, u8), String> {
+    let invalid = || format!("route({route}): expected <ipv4>/<prefix length>");
+    let (addr, len) = route.split_once('/').ok_or_else(invalid)?;
+    let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+    let len: u8 = len.parse().ok().filter(|len| *len <= 32).ok_or_else(invalid)?;
+    let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+    if u32::from(addr) & !mask != 0 {
+        return Err(format!("route({route}): host bits set"));
+    }
+    Ok((addr, len))
+}
+
+/// Routes and DNS servers set up for the TUN device. They go away with the device,
+/// except for the route keeping the proxy off it, which is removed on drop.
+pub struct Setup {
+    // `ip route` arguments of the proxy route
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    bypass: Option<Vec<String>>,
+}
+
+impl Setup {
+    /// Route `system.routes` into `device` and make `system.dns` its resolvers. The proxy keeps its
+    /// current route, so its connections do not loop back into the device.
+    #[cfg(target_os = "linux")]
+    pub fn apply(device: &str, system: &System, proxy: Option<SocketAddr>) -> Result<Self, String> {
+        let mut setup = Setup { bypass: None };
+
+        if let (Some(proxy), false) = (proxy, system.routes.is_empty()) {
+            if let Some(bypass) = current_route(proxy.ip())? {
+                run("ip", &route_args("add", &bypass))?;
+                setup.bypass = Some(bypass);
+            }
+        }
+        for route in &system.routes {
+            let (addr, len) = parse_route(route)?;
+            let route = [format!("{addr}/{len}"), "dev".to_string(), device.to_string()];
+            run("ip", &route_args("add", &route))?;
+        }
+
+        if !system.dns.is_empty() {
+            let mut args = vec!["dns".to_string(), device.to_string()];
+            args.extend(system.dns.iter().map(IpAddr::to_string));
+            run("resolvectl", &args)?;
+            // Send every lookup to them, not only those of a search domain
+            run("resolvectl", &["domain".to_string(), device.to_string(), "~.".to_string()])?;
+        }
+        Ok(setup)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(_device: &str, _system: &System, _proxy: Option<SocketAddr>) -> Result<Self, String> {
+        Err("--setup-system is only supported on Linux".to_string())
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Some(bypass) = self.bypass.take() {
+            run("ip", &route_args("del", &bypass)).unwrap_or_else(|err| eprintln!("tun2socks: {err}"));
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn route_args(action: &str, route: &[String]) -> Vec<String> {
+    let mut args = vec!["route".to_string(), action.to_string()];
+    args.extend(route.iter().cloned());
+    args
+}
+
+// The route `ip` takes to reach `addr` now, as `ip route add` arguments. None when it is local
+#[cfg(target_os = "linux")]
+fn current_route(addr: IpAddr) -> Result<Option<Vec<String>>, String> {
+    let output = Command::new("ip")
+        .args(["route", "get", &addr.to_string()])
+        .output()
+        .map_err(|err| format!("ip route get {addr}: {err}"))?;
+    if !output.status.success() {
+        return Err(format!("ip route get {addr}: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    let words: Vec<String> = String::from_utf8_lossy(&output.stdout).split_whitespace().map(str::to_string).collect();
+    let after = |key: &str| words.iter().position(|word| word == key).and_then(|i| words.get(i + 1)).cloned();
+    match (after("via"), after("dev")) {
+        (Some(via), Some(dev)) => Ok(Some(vec![addr.to_string(), "via".to_string(), via, "dev".to_string(), dev])),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn run(program: &str, args: &[String]) -> Result<(), String> {
+    let output = Command::new(program).args(args).output().map_err(|err| format!("{program}: {err}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{program} {}: {}", args.join(" "), stderr.trim()));
+    }
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::capture;
+use crate::device::PacketFraming;
+use crate::logging::Rotation;
+use crate::util::percent_decode;
+
+/// Runtime settings of one tun2socks instance.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Upper bound of concurrently tracked flows (TCP connections, UDP sessions).
+    pub max_flows: usize,
+    /// What happens to a new flow once `max_flows` is reached.
+    pub overflow_policy: OverflowPolicy,
+    /// How long flows may stay idle before they are closed.
+    pub timeouts: Timeouts,
+    /// Relay TCP flows through this SOCKS5 server, UDP always goes direct.
+    pub proxy: Option<Proxy>,
+    pub dns: DnsMode,
+    /// Rotation of the log file, if there is one.
+    pub log_rotation: Rotation,
+    /// Write the packets passing the interface into a pcapng file.
+    pub capture: Option<capture::Options>,
+    /// Header in front of the packets on the interface, the one of the platform's TUN device by default.
+    /// With `VirtioNet` and TSO enabled on the device TCP data goes down as super-packets of up to 64k.
+    pub framing: PacketFraming,
+    /// Drop packets from the interface whose IP, TCP, UDP or ICMP checksum does not match. Off by
+    /// default, the local stack seldom gets them wrong. Checksums left to the device by virtio-net
+    /// offload (NEEDS_CSUM) are not checked.
+    pub verify_checksums: bool,
+    /// Address traceroute shows as the first hop: packets whose time to live runs out at the
+    /// interface get ICMP time exceeded from it, from their own destination when not set.
+    pub gateway: Option<Ipv4Addr>,
+    /// Serve the counters at `http://127.0.0.1:<port>/metrics`, 0 picks a free port.
+    #[cfg(feature = "metrics")]
+    pub metrics_port: Option<u16>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_flows: DEFAULT_MAX_FLOWS,
+            overflow_policy: OverflowPolicy::EvictLru,
+            timeouts: Timeouts::default(),
+            proxy: None,
+            dns: DnsMode::Direct,
+            log_rotation: Rotation::default(),
+            capture: None,
+            framing: PacketFraming::default(),
+            verify_checksums: false,
+            gateway: None,
+            #[cfg(feature = "metrics")]
+            metrics_port: None,
+        }
+    }
+}
+
+/// Idle timeouts per kind of flow, checked by the sweeper about once a second.
+#[derive(Debug, Copy, Clone)]
+pub struct Timeouts {
+    /// Upstream connect attempt, counted from the SYN.
+    pub tcp_connect: Duration,
+    /// TCP connection with both directions open.
+    pub tcp_established: Duration,
+    /// TCP connection after one side sent its FIN.
+    pub tcp_half_closed: Duration,
+    pub udp: Duration,
+    /// UDP session with port 53.
+    pub dns: Duration,
+    pub icmp: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            tcp_connect: Duration::from_secs(5),
+            tcp_established: Duration::from_secs(600),
+            tcp_half_closed: Duration::from_secs(60),
+            udp: Duration::from_secs(60),
+            dns: Duration::from_secs(10),
+            icmp: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A SOCKS5 server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub addr: SocketAddr,
+    /// Offer username/password authentication (RFC 1929) besides none.
+    pub auth: Option<Auth>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Auth {
+    pub username: String,
+    pub password: String,
+}
+
+impl Proxy {
+    /// Parse `socks5://[user[:password]@]host:port`, the user and password may be percent-encoded.
+    /// A host name is resolved right away.
+    pub fn parse_url(url: &str) -> Result<Self> {
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidInput, format!("proxy({url}): {message}"));
+        let rest = url.strip_prefix("socks5://").ok_or_else(|| invalid("only socks5:// is supported"))?;
+        let rest = rest.strip_suffix('/').unwrap_or(rest);
+        let (userinfo, host) = match rest.rsplit_once('@') {
+            Some((userinfo, host)) => (Some(userinfo), host),
+            None => (None, rest),
+        };
+
+        let auth = match userinfo {
+            Some(userinfo) => {
+                let (username, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                let decode = |s: &str| percent_decode(s).ok_or_else(|| invalid("invalid percent-encoding"));
+                Some(Auth { username: decode(username)?, password: decode(password)? })
+            }
+            None => None,
+        };
+        let addr = host
+            .to_socket_addrs()
+            .map_err(|err| invalid(&err.to_string()))?
+            .next()
+            .ok_or_else(|| invalid("host has no address"))?;
+        Ok(Self { addr, auth })
+    }
+}
+
+// Keep the password out of the logs
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth").field("username", &self.username).field("password", &"***").finish()
+    }
+}
+
+/// Where UDP flows to port 53 go.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DnsMode {
+    /// To the resolver the client asked.
+    Direct,
+    /// To this resolver instead, the client still sees the answers come from the one it asked.
+    Server(SocketAddr),
+}
+
+impl DnsMode {
+    /// `direct`, or the address of a resolver with an optional port, 53 by default.
+    pub fn parse(mode: &str) -> Result<Self> {
+        if mode == "direct" {
+            return Ok(DnsMode::Direct);
+        }
+        let addr = mode.parse::<SocketAddr>().or_else(|_| mode.parse().map(|ip| SocketAddr::new(ip, 53)));
+        addr.map(DnsMode::Server)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("dns({mode}): neither direct nor an address")))
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Refuse the new flow: TCP gets a RST, anything else ICMP destination unreachable (administratively prohibited).
+    Reset = 0,
+    /// Refuse the new flow with ICMP destination unreachable (administratively prohibited).
+    Unreachable = 1,
+    /// Close the least recently active flow and give its slot to the new one.
+    EvictLru = 2,
+}
+
+#[cfg(not(feature = "mobile"))]
+pub const DEFAULT_MAX_FLOWS: usize = 1024;
+#[cfg(feature = "mobile")]
+pub const DEFAULT_MAX_FLOWS: usize = 256;
@@ -0,0 +1,134 @@
+use std::io::{Error, Result};
+use std::os::fd::RawFd;
+
+// Packets moved per system call at most
+pub const BATCH: usize = 32;
+
+// Reads and writes several packets per system call with recvmmsg and sendmmsg where the fd is a
+// socket, e.g. the socketpair a host hands over. A TUN fd is none, it gets one read or write per
+// packet: the first ENOTSOCK switches over for good.
+pub struct Batch {
+    mmsg: bool,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self { mmsg: cfg!(any(target_os = "linux", target_os = "android")) }
+    }
+}
+
+impl Batch {
+    // Fill up to `BATCH` of `bufs` with one packet each, their lengths into `lens`. Returns the number
+    // of packets, 0 once the fd reached its end, or `WouldBlock` when there is none yet.
+    // A packet of length 0 ends the batch, it reads as the end of the fd on the next call.
+    pub fn read<T: AsMut<[u8]>>(&mut self, fd: RawFd, bufs: &mut [T], lens: &mut [usize]) -> Result<usize> {
+        let count = bufs.len().min(lens.len()).min(BATCH);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.mmsg {
+            match recvmmsg(fd, &mut bufs[..count], &mut lens[..count]) {
+                Err(err) if err.raw_os_error() == Some(libc::ENOTSOCK) => self.mmsg = false,
+                result => return result,
+            }
+        }
+
+        let mut n = 0;
+        while n < count {
+            let buf = bufs[n].as_mut();
+            let read = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+            if read < 0 {
+                let err = Error::last_os_error();
+                if n == 0 {
+                    return Err(err);
+                }
+                break; // Seen again by the next call
+            }
+            if read == 0 {
+                break; // The end, alone on the next call
+            }
+            lens[n] = read as usize;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    // Write the first `BATCH` of `frames`, one packet each, packet fds take a packet whole. Returns
+    // how many went out from the front, or the error of the first.
+    pub fn write<T: AsRef<[u8]>>(&mut self, fd: RawFd, frames: &[T]) -> Result<usize> {
+        let count = frames.len().min(BATCH);
+        if count == 0 {
+            return Ok(0);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.mmsg {
+            match sendmmsg(fd, &frames[..count]) {
+                Err(err) if err.raw_os_error() == Some(libc::ENOTSOCK) => self.mmsg = false,
+                result => return result,
+            }
+        }
+
+        let mut n = 0;
+        while n < count {
+            let frame = frames[n].as_ref();
+            let written = unsafe { libc::write(fd, frame.as_ptr().cast(), frame.len()) };
+            if written < 0 {
+                let err = Error::last_os_error();
+                if n == 0 {
+                    return Err(err);
+                }
+                break;
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn recvmmsg<T: AsMut<[u8]>>(fd: RawFd, bufs: &mut [T], lens: &mut [usize]) -> Result<usize> {
+    let mut iovecs: [libc::iovec; BATCH] = unsafe { std::mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; BATCH] = unsafe { std::mem::zeroed() };
+    for (i, buf) in bufs.iter_mut().enumerate() {
+        let buf = buf.as_mut();
+        iovecs[i] = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+        msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+        msgs[i].msg_hdr.msg_iovlen = 1;
+    }
+
+    let n = unsafe {
+        libc::recvmmsg(fd, msgs.as_mut_ptr(), bufs.len() as _, libc::MSG_DONTWAIT, std::ptr::null_mut())
+    };
+    if n < 0 {
+        return Err(Error::last_os_error());
+    }
+    let n = n as usize;
+    for (i, msg) in msgs[..n].iter().enumerate() {
+        lens[i] = msg.msg_len as usize;
+        if msg.msg_len == 0 {
+            return Ok(i);
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sendmmsg<T: AsRef<[u8]>>(fd: RawFd, frames: &[T]) -> Result<usize> {
+    let mut iovecs: [libc::iovec; BATCH] = unsafe { std::mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; BATCH] = unsafe { std::mem::zeroed() };
+    for (i, frame) in frames.iter().enumerate() {
+        let frame = frame.as_ref();
+        iovecs[i] = libc::iovec { iov_base: frame.as_ptr() as *mut _, iov_len: frame.len() };
+        msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+        msgs[i].msg_hdr.msg_iovlen = 1;
+    }
+
+    loop {
+        let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), frames.len() as _, libc::MSG_DONTWAIT) };
+        if n >= 0 {
+            return Ok(n as usize);
+        }
+        let err = Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
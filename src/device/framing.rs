@@ -0,0 +1,108 @@
+use std::os::raw::c_int;
+
+use crate::device::vnet;
+
+/// What comes in front of each IP packet read from or written to the interface.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PacketFraming {
+    /// Bare IP packets: Linux with IFF_NO_PI, Android VpnService, NEPacketTunnelFlow.
+    Raw = 0,
+    /// The address family as 4 bytes in network byte order: utun on macOS and iOS.
+    AfHeader = 1,
+    /// struct tun_pi, 2 bytes of flags and the EtherType: Linux without IFF_NO_PI.
+    TunPi = 2,
+    /// struct virtio_net_hdr: Linux with IFF_VNET_HDR, see `vnet`.
+    VirtioNet = 3,
+}
+
+// The file descriptor of utun has the header, the other platforms hand out bare packets
+impl Default for PacketFraming {
+    fn default() -> Self {
+        if cfg!(any(target_os = "macos", target_os = "ios")) {
+            PacketFraming::AfHeader
+        } else {
+            PacketFraming::Raw
+        }
+    }
+}
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86DD;
+// tun_pi flag: the packet did not fit the read buffer
+const TUN_PKT_STRIP: u16 = 0x0001;
+
+impl PacketFraming {
+    pub fn from_raw(framing: c_int) -> Option<Self> {
+        match framing {
+            0 => Some(PacketFraming::Raw),
+            1 => Some(PacketFraming::AfHeader),
+            2 => Some(PacketFraming::TunPi),
+            3 => Some(PacketFraming::VirtioNet),
+            _ => None,
+        }
+    }
+
+    pub fn header_len(self) -> usize {
+        match self {
+            PacketFraming::Raw => 0,
+            PacketFraming::AfHeader | PacketFraming::TunPi => 4,
+            PacketFraming::VirtioNet => vnet::HEADER_LEN,
+        }
+    }
+
+    /// The IP packet of a `frame` read from the interface. None when the frame is shorter than
+    /// its header, or the header announces anything but IPv4 or IPv6.
+    pub fn unframe(self, frame: &[u8]) -> Option<&[u8]> {
+        if frame.len() < self.header_len() {
+            return None;
+        }
+        let (header, packet) = frame.split_at(self.header_len());
+        let valid = match self {
+            PacketFraming::Raw | PacketFraming::VirtioNet => true,
+            PacketFraming::AfHeader => {
+                let family = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                family == libc::AF_INET as u32 || family == libc::AF_INET6 as u32
+            }
+            PacketFraming::TunPi => {
+                let flags = u16::from_ne_bytes([header[0], header[1]]);
+                let protocol = u16::from_be_bytes([header[2], header[3]]);
+                flags & TUN_PKT_STRIP == 0 && (protocol == ETHERTYPE_IPV4 || protocol == ETHERTYPE_IPV6)
+            }
+        };
+        valid.then_some(packet)
+    }
+
+    /// `packet` with the header it is written to the interface with, the family taken from its
+    /// version. Over virtio-net a TCP packet larger than `mtu` goes as a super-packet.
+    pub fn frame(self, packet: &[u8], mtu: usize) -> Vec<u8> {
+        let mut frame = vec![0; self.header_len() + packet.len()];
+        self.frame_into(packet, mtu, &mut frame);
+        frame
+    }
+
+    /// `frame` into the front of `out`, returns the length of the frame.
+    /// Panics when `out` is shorter than `header_len() + packet.len()`.
+    pub fn frame_into(self, packet: &[u8], mtu: usize, out: &mut [u8]) -> usize {
+        let ipv6 = packet.first().is_some_and(|byte| byte >> 4 == 6);
+        let len = self.header_len() + packet.len();
+        let (header, body) = out[..len].split_at_mut(self.header_len());
+        body.copy_from_slice(packet);
+        match self {
+            PacketFraming::Raw => {}
+            PacketFraming::AfHeader => {
+                let family = if ipv6 { libc::AF_INET6 } else { libc::AF_INET };
+                header.copy_from_slice(&(family as u32).to_be_bytes());
+            }
+            PacketFraming::TunPi => {
+                let protocol = if ipv6 { ETHERTYPE_IPV6 } else { ETHERTYPE_IPV4 };
+                header[..2].copy_from_slice(&0u16.to_ne_bytes());
+                header[2..].copy_from_slice(&protocol.to_be_bytes());
+            }
+            PacketFraming::VirtioNet => {
+                header.copy_from_slice(&vnet::Header::for_packet(body, mtu).to_bytes());
+            }
+        }
+        len
+    }
+}
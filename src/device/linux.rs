@@ -0,0 +1,137 @@
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::{c_char, c_short, c_ulong};
+
+/// How to create the TUN device.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Interface name, without one the kernel picks the next free `tunN`.
+    pub name: Option<String>,
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// Peer address of the point-to-point link.
+    pub destination: Ipv4Addr,
+    pub mtu: u16,
+    /// File descriptors to open on the device. More than one sets IFF_MULTI_QUEUE,
+    /// the kernel then keeps each flow on one of them.
+    pub queues: usize,
+    /// Set IFF_VNET_HDR and enable TSO: every packet starts with a `vnet::Header`
+    /// and TCP comes and goes as super-packets of up to 64k.
+    pub vnet_hdr: bool,
+}
+
+/// A TUN device, up and addressed. It goes away once all its queues are closed.
+pub struct Device {
+    name: String,
+    queues: Vec<File>,
+}
+
+impl Device {
+    pub fn create(options: &Options) -> Result<Self> {
+        let count = options.queues.max(1);
+        // Packets without the 4 bytes of struct tun_pi in front
+        let mut flags = libc::IFF_TUN | libc::IFF_NO_PI;
+        if count > 1 {
+            flags |= libc::IFF_MULTI_QUEUE;
+        }
+        if options.vnet_hdr {
+            flags |= libc::IFF_VNET_HDR;
+        }
+
+        let mut name = options.name.clone().unwrap_or_default();
+        let mut queues = Vec::with_capacity(count);
+        for _ in 0..count {
+            let queue = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/net/tun")
+                .map_err(|err| Error::new(err.kind(), format!("open /dev/net/tun: {err}")))?;
+            let mut req = ifreq(&name)?;
+            req.ifr_ifru.ifru_flags = flags as c_short;
+            ioctl(queue.as_raw_fd(), libc::TUNSETIFF, &mut req, "TUNSETIFF")?;
+            // The next queues attach to the device the first one created
+            name = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }.to_string_lossy().into_owned();
+
+            if options.vnet_hdr {
+                let offload = libc::TUN_F_CSUM | libc::TUN_F_TSO4 | libc::TUN_F_TSO6;
+                if unsafe { libc::ioctl(queue.as_raw_fd(), libc::TUNSETOFFLOAD, offload as c_ulong) } < 0 {
+                    let err = Error::last_os_error();
+                    return Err(Error::new(err.kind(), format!("TUNSETOFFLOAD {name}: {err}")));
+                }
+            }
+            queues.push(queue);
+        }
+
+        configure(&name, options)?;
+        Ok(Self { name, queues })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// One file per queue, to be read and written by its own dispatcher.
+    pub fn into_queues(self) -> Vec<File> {
+        self.queues
+    }
+}
+
+// Address the device, set its MTU and bring it up
+fn configure(name: &str, options: &Options) -> Result<()> {
+    let socket = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if socket < 0 {
+        return Err(Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+    let fd = socket.as_raw_fd();
+
+    let mut req = ifreq(name)?;
+    req.ifr_ifru.ifru_addr = sockaddr(options.address);
+    ioctl(fd, libc::SIOCSIFADDR, &mut req, "SIOCSIFADDR")?;
+    req.ifr_ifru.ifru_dstaddr = sockaddr(options.destination);
+    ioctl(fd, libc::SIOCSIFDSTADDR, &mut req, "SIOCSIFDSTADDR")?;
+    // After the address, which resets it
+    req.ifr_ifru.ifru_netmask = sockaddr(options.netmask);
+    ioctl(fd, libc::SIOCSIFNETMASK, &mut req, "SIOCSIFNETMASK")?;
+    req.ifr_ifru.ifru_mtu = options.mtu.into();
+    ioctl(fd, libc::SIOCSIFMTU, &mut req, "SIOCSIFMTU")?;
+
+    ioctl(fd, libc::SIOCGIFFLAGS, &mut req, "SIOCGIFFLAGS")?;
+    unsafe { req.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as c_short };
+    ioctl(fd, libc::SIOCSIFFLAGS, &mut req, "SIOCSIFFLAGS")
+}
+
+fn ifreq(name: &str) -> Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("device name({name}) must be shorter than {} bytes", libc::IFNAMSIZ)));
+    }
+    let mut req: libc::ifreq = unsafe { mem::zeroed() };
+    for (slot, byte) in req.ifr_name.iter_mut().zip(name.bytes()) {
+        *slot = byte as c_char;
+    }
+    Ok(req)
+}
+
+fn sockaddr(addr: Ipv4Addr) -> libc::sockaddr {
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr { s_addr: u32::from(addr).to_be() },
+        sin_zero: [0; 8],
+    };
+    // Both are 16 bytes, sockaddr being the generic view
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(addr) }
+}
+
+fn ioctl(fd: RawFd, request: libc::Ioctl, req: &mut libc::ifreq, name: &str) -> Result<()> {
+    if unsafe { libc::ioctl(fd, request, req as *mut libc::ifreq) } < 0 {
+        let err = Error::last_os_error();
+        let device = unsafe { CStr::from_ptr(req.ifr_name.as_ptr()) }.to_string_lossy().into_owned();
+        return Err(Error::new(err.kind(), format!("{name} {device}: {err}")));
+    }
+    Ok(())
+}
@@ -0,0 +1,11 @@
+// TUN devices tun2socks creates itself and the headers their packets may carry
+pub mod vnet;
+pub mod batch;
+mod framing;
+
+#[cfg(target_os = "linux")]
+mod linux;
+
+pub use framing::PacketFraming;
+#[cfg(target_os = "linux")]
+pub use linux::{Device, Options};
@@ -0,0 +1,112 @@
+use crate::protocol::internet::checksum;
+
+/*
+struct virtio_net_hdr, in front of every packet of a Linux TUN device created with IFF_VNET_HDR.
+The fields are in host byte order:
+
+        +-------+----------+---------+----------+------------+-------------+
+        | flags | gso_type | hdr_len | gso_size | csum_start | csum_offset |
+        +-------+----------+---------+----------+------------+-------------+
+        |   1   |    1     |    2    |    2     |     2      |      2      |
+        +-------+----------+---------+----------+------------+-------------+
+
+With a gso_type other than GSO_NONE the packet is a super-packet, cut into segments of gso_size bytes
+of payload after the first hdr_len bytes. F_NEEDS_CSUM: the checksum at csum_start + csum_offset only
+covers the pseudo header yet, the rest of the packet from csum_start on is still to be added.
+ */
+pub const HEADER_LEN: usize = 10;
+
+pub const F_NEEDS_CSUM: u8 = 1;
+
+pub const GSO_NONE: u8 = 0;
+pub const GSO_TCPV4: u8 = 1;
+pub const GSO_UDP: u8 = 3;
+pub const GSO_TCPV6: u8 = 4;
+pub const GSO_UDP_L4: u8 = 5;
+pub const GSO_ECN: u8 = 0x80;
+
+// Largest IP packet, super-packets included
+pub const MAX_PACKET: usize = 65535;
+
+const TCP: u8 = 6;
+// Offset of the checksum in the TCP header
+const TCP_CHECKSUM: usize = 16;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl Header {
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let field = |at: usize| u16::from_ne_bytes([bytes[at], bytes[at + 1]]);
+        Some(Self {
+            flags: bytes[0],
+            gso_type: bytes[1],
+            hdr_len: field(2),
+            gso_size: field(4),
+            csum_start: field(6),
+            csum_offset: field(8),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0] = self.flags;
+        bytes[1] = self.gso_type;
+        bytes[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        bytes[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        bytes[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        bytes[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        bytes
+    }
+
+    /// Header to write the IP `packet` with. A TCP packet larger than `mtu` goes as a super-packet the
+    /// kernel segments: its checksum is rewritten to the pseudo header sum, completed per segment.
+    /// Anything else needs no offload, its checksums being complete.
+    pub fn for_packet(packet: &mut [u8], mtu: usize) -> Self {
+        if packet.len() <= mtu || packet.is_empty() {
+            return Self::default();
+        }
+        let (ip_len, protocol, gso_type) = match packet[0] >> 4 {
+            4 if packet.len() >= 20 => (((packet[0] & 0x0F) as usize) * 4, packet[9], GSO_TCPV4),
+            6 if packet.len() >= 40 => (40, packet[6], GSO_TCPV6),
+            _ => return Self::default(),
+        };
+        if protocol != TCP || packet.len() < ip_len + 20 {
+            return Self::default();
+        }
+        let hdr_len = ip_len + ((packet[ip_len + 12] >> 4) as usize) * 4;
+        if hdr_len >= mtu || hdr_len > packet.len() {
+            return Self::default();
+        }
+
+        let sum = pseudo_header_sum(packet, ip_len);
+        packet[ip_len + TCP_CHECKSUM..ip_len + TCP_CHECKSUM + 2].copy_from_slice(&sum.to_be_bytes());
+        Self {
+            flags: F_NEEDS_CSUM,
+            gso_type,
+            hdr_len: hdr_len as u16,
+            gso_size: (mtu - hdr_len) as u16,
+            csum_start: ip_len as u16,
+            csum_offset: TCP_CHECKSUM as u16,
+        }
+    }
+}
+
+// Folded, not complemented, one's complement sum of the TCP pseudo header of `packet`
+fn pseudo_header_sum(packet: &[u8], ip_len: usize) -> u16 {
+    let (src, dst) = match packet[0] >> 4 {
+        4 => (&packet[12..16], &packet[16..20]),
+        _ => (&packet[8..24], &packet[24..40]),
+    };
+    checksum::pseudo_header_sum(src, dst, TCP, packet.len() - ip_len)
+}
@@ -0,0 +1,165 @@
+use std::io::Result;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::os::fd::{AsRawFd, RawFd};
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::io::{Error, ErrorKind};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::ptr;
+
+use crate::dispatcher::Dispatcher;
+use crate::dispatcher::flow::Upstream;
+use crate::protocol::internet::icmp::{self, Unreachable, DESTINATION_UNREACHABLE, FRAGMENTATION_NEEDED, TIME_EXCEEDED};
+use crate::protocol::internet::checksum;
+
+// ICMP error a hop out there, or the destination, sent about a datagram of an upstream socket
+pub struct HopError {
+    pub from: Ipv4Addr,
+    // Where the datagram was sent to
+    pub to: SocketAddrV4,
+    pub tp: u8,
+    pub code: u8,
+    // Next hop MTU of a fragmentation needed
+    pub info: u32,
+    // Bytes of the datagram's payload the error quoted, read into the buffer
+    pub len: usize,
+}
+
+// Queue the ICMP errors about the datagrams of the socket for `recv_error`. Linux only, elsewhere the
+// sockets keep reporting the destination's errors and nothing else
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn enable(fd: RawFd) -> Result<()> {
+    let on: libc::c_int = 1;
+    let len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe { libc::setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVERR, (&on as *const libc::c_int).cast(), len) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn enable(_fd: RawFd) -> Result<()> {
+    Ok(())
+}
+
+// Next ICMP error of the queue, None once it is empty. Errors of the local stack are skipped
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn recv_error(fd: RawFd, buf: &mut [u8]) -> Result<Option<HopError>> {
+    loop {
+        let mut iov = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+        // sock_extended_err and the offender's address, u64 for the alignment of cmsghdr
+        let mut control = [0u64; 16];
+        let mut to: libc::sockaddr_in = unsafe { mem::zeroed() };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = (&mut to as *mut libc::sockaddr_in).cast();
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_in>() as _;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+        let n = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if n < 0 {
+            let err = Error::last_os_error();
+            match err.kind() {
+                ErrorKind::WouldBlock => return Ok(None),
+                ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            if header.cmsg_level == libc::IPPROTO_IP && header.cmsg_type == libc::IP_RECVERR {
+                let ee = unsafe { libc::CMSG_DATA(cmsg) } as *const libc::sock_extended_err;
+                let err = unsafe { ptr::read_unaligned(ee) };
+                if err.ee_origin == libc::SO_EE_ORIGIN_ICMP {
+                    let offender = unsafe { ptr::read_unaligned(libc::SO_EE_OFFENDER(ee) as *const libc::sockaddr_in) };
+                    return Ok(Some(HopError {
+                        from: Ipv4Addr::from(u32::from_be(offender.sin_addr.s_addr)),
+                        to: SocketAddrV4::new(Ipv4Addr::from(u32::from_be(to.sin_addr.s_addr)), u16::from_be(to.sin_port)),
+                        tp: err.ee_type,
+                        code: err.ee_code,
+                        info: err.ee_info,
+                        len: n as usize,
+                    }));
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn recv_error(_fd: RawFd, _buf: &mut [u8]) -> Result<Option<HopError>> {
+    Ok(None)
+}
+
+impl Dispatcher {
+    // Time exceeded and destination unreachable the upstream datagrams of the flow ran into, relayed to
+    // the client from their sender so that traceroute shows the hops out there. None when there were
+    // none, otherwise whether the destination was unreachable, which ends the flow
+    pub(crate) fn relay_errors(&mut self, id: usize) -> Option<bool> {
+        let mut buf = mem::take(&mut self.buf);
+        let mut relayed = None;
+        while let Some(flow) = self.router.flow(id) {
+            let fd = match &flow.upstream {
+                Upstream::Udp(socket) => socket.as_raw_fd(),
+                Upstream::Icmp(socket) => socket.as_raw_fd(),
+                _ => break,
+            };
+            let err = match recv_error(fd, &mut buf) {
+                Ok(Some(err)) => err,
+                Ok(None) => break,
+                Err(err) => {
+                    flow_log!(self, Debug, Icmp, id, "error queue: {err}");
+                    break;
+                }
+            };
+
+            // An echo request is told apart by its sequence number, quoted from the one that failed, a
+            // datagram of a UDP session by the remote it went to
+            let mut origin = flow.datagram.origin.clone();
+            match &flow.upstream {
+                Upstream::Icmp(_) if err.len >= 8 && origin.len() >= 28 => {
+                    let seq = origin.len() - 2;
+                    origin[seq..].copy_from_slice(&buf[6..8]);
+                }
+                Upstream::Udp(_) if origin.len() >= 28 => {
+                    if let SocketAddr::V4(dst) = flow.inbound(SocketAddr::V4(err.to)) {
+                        let udp = origin.len() - 8;
+                        let old_ip = u32::from_be_bytes([origin[16], origin[17], origin[18], origin[19]]);
+                        let old_port = u16::from_be_bytes([origin[udp + 2], origin[udp + 3]]);
+                        let (new_ip, new_port) = (u32::from(*dst.ip()), dst.port());
+                        origin[16..20].copy_from_slice(&new_ip.to_be_bytes());
+                        origin[udp + 2..udp + 4].copy_from_slice(&new_port.to_be_bytes());
+                        let sum = checksum::update_u32(u16::from_be_bytes([origin[10], origin[11]]), old_ip, new_ip);
+                        origin[10..12].copy_from_slice(&sum.to_be_bytes());
+                        // The UDP checksum also covers the destination of the pseudo header, zero is none
+                        let sum = u16::from_be_bytes([origin[udp + 6], origin[udp + 7]]);
+                        if sum != 0 {
+                            let sum = checksum::update(checksum::update_u32(sum, old_ip, new_ip), old_port, new_port);
+                            origin[udp + 6..udp + 8].copy_from_slice(&sum.to_be_bytes());
+                        }
+                    }
+                }
+                _ => {}
+            }
+            let message = match err.tp {
+                TIME_EXCEEDED => icmp::time_exceeded(err.code, &origin),
+                DESTINATION_UNREACHABLE => {
+                    let next_hop_mtu = if err.code == FRAGMENTATION_NEEDED { err.info as u16 } else { 0 };
+                    icmp::message(DESTINATION_UNREACHABLE, err.code, &Unreachable { next_hop_mtu, origin })
+                }
+                _ => continue,
+            };
+            let pkt = flow.datagram.icmp_resp_pack_from(err.from.octets(), &message);
+            flow_log!(self, Debug, Icmp, id, "icmp type({}) code({}) from {}", err.tp, err.code, err.from);
+            self.respond(&pkt);
+            relayed = Some(relayed == Some(true) || err.tp == DESTINATION_UNREACHABLE);
+        }
+        self.buf = buf;
+        relayed
+    }
+}
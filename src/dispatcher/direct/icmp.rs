@@ -0,0 +1,250 @@
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+use crate::dispatcher::{refusal, set_nonblocking, Dispatcher};
+use crate::dispatcher::direct::hops;
+use crate::dispatcher::flow::{Flow, IcmpState, State, Upstream};
+use crate::logging::{log, Hex};
+use crate::protocol::internet::icmp::{self, ECHO_REPLY};
+use crate::protocol::internet::Datagram;
+use crate::protocol::internet::view::FlowKey;
+use crate::stats::Protocol;
+
+// An unprivileged ICMP socket (SOCK_DGRAM, IPPROTO_ICMP) connected to one destination. It takes echo
+// requests without the IP header, the kernel sets their identifier and matches the replies by it.
+// Linux allows it to the groups in net.ipv4.ping_group_range, macOS and iOS to anyone.
+pub struct IcmpSocket {
+    fd: OwnedFd,
+}
+
+impl IcmpSocket {
+    pub fn connect(dst: Ipv4Addr) -> Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_ICMP) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let socket = Self { fd: unsafe { OwnedFd::from_raw_fd(fd) } };
+        set_nonblocking(fd)?;
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        hops::enable(fd)?;
+
+        let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        {
+            addr.sin_len = mem::size_of::<libc::sockaddr_in>() as u8;
+        }
+        addr.sin_family = libc::AF_INET as libc::sa_family_t;
+        addr.sin_addr = libc::in_addr { s_addr: u32::from(dst).to_be() };
+        let len = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        if unsafe { libc::connect(fd, (&addr as *const libc::sockaddr_in).cast(), len) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    pub fn set_ttl(&self, ttl: u8) -> Result<()> {
+        let ttl = libc::c_int::from(ttl);
+        let len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        if unsafe { libc::setsockopt(self.fd.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TTL, (&ttl as *const libc::c_int).cast(), len) } < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn send(&self, message: &[u8]) -> Result<usize> {
+        let n = unsafe { libc::send(self.fd.as_raw_fd(), message.as_ptr().cast(), message.len(), 0) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    // One ICMP message. macOS hands it over with the IP header in front, it is stripped
+    pub fn recv(&self, buf: &mut [u8]) -> Result<(usize, usize)> {
+        let n = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if n < 0 {
+            return Err(Error::last_os_error());
+        }
+        let n = n as usize;
+        // No ICMP type has 4 in its upper bits
+        let start = match buf.first() {
+            Some(first) if n >= 20 && first >> 4 == 4 => ((first & 0x0F) as usize * 4).min(n),
+            _ => 0,
+        };
+        Ok((start, n))
+    }
+}
+
+impl AsRawFd for IcmpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Source for IcmpSocket {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).deregister(registry)
+    }
+}
+
+impl Dispatcher {
+    // An echo request with an identifier of its own, sent from a new ICMP socket
+    pub(crate) fn open_icmp(&mut self, id: usize, key: FlowKey, datagram: Datagram) {
+        let IpAddr::V4(dst) = key.dst.ip() else {
+            return;
+        };
+        if !self.icmp_sockets {
+            self.echo_locally(key, &datagram);
+            return;
+        }
+
+        self.stats.connect_attempt();
+        let socket = match IcmpSocket::connect(dst) {
+            Ok(socket) => socket,
+            Err(err) if not_permitted(&err) => {
+                log!(self.logging, Info, Icmp, flow = id, tuple = key; "icmp sockets not permitted: {err}, echo replies made up locally from now on");
+                self.stats.connect_failure();
+                self.icmp_sockets = false;
+                self.echo_locally(key, &datagram);
+                return;
+            }
+            Err(err) => {
+                log!(self.logging, Info, Icmp, flow = id, tuple = key; "socket error: {err}");
+                self.stats.connect_failure();
+                if let Some(pkt) = refusal(&datagram, &err) {
+                    self.respond(&pkt);
+                }
+                return;
+            }
+        };
+
+        let mut upstream = Upstream::Icmp(socket);
+        if let Err(err) = self.register(id, &mut upstream) {
+            log!(self.logging, Error, Icmp, flow = id, tuple = key; "register error: {err}");
+            self.stats.connect_failure();
+            return;
+        }
+
+        let mut flow = Flow::new(id, key, State::ICMP(IcmpState::Communication), datagram, upstream);
+        flow.upstream_addr = key.dst;
+        flow.domain = self.domain(key.dst);
+        self.router.insert(flow);
+        self.stats.flow_opened(Protocol::Icmp);
+        log!(self.logging, Debug, Icmp, flow = id, tuple = key; "echo to {dst}");
+        self.handle_icmp(id);
+    }
+
+    // Send the latest echo request of the flow
+    pub(crate) fn handle_icmp(&mut self, id: usize) {
+        let mut relayed = false;
+        loop {
+            let flow = match self.router.flow(id) {
+                Some(flow) => flow,
+                None => return,
+            };
+            let ttl = flow.datagram.header.ttl;
+            // Type, code and a checksum left to the kernel, then identifier, sequence number and data
+            let mut request = vec![icmp::ECHO_REQUEST, 0, 0, 0];
+            request.extend_from_slice(flow.datagram.payload.payload());
+            let sent = flow.set_ttl(ttl).and_then(|()| match &flow.upstream {
+                Upstream::Icmp(socket) => socket.send(&request),
+                _ => Ok(0),
+            });
+
+            match sent {
+                Ok(n) => {
+                    flow.bytes_up += n as u64;
+                    self.stats.bytes_up(n);
+                    flow_log!(self, Trace, Icmp, id, "sent {n} bytes");
+                    return;
+                }
+                // An error about an earlier request fails the send, relayed it goes again
+                Err(err) => match (relayed, self.relay_errors(id)) {
+                    (false, Some(false)) => relayed = true,
+                    (_, Some(true)) => {
+                        self.close(id, None);
+                        return;
+                    }
+                    _ => {
+                        flow_log!(self, Info, Icmp, id, "send error: {err}");
+                        self.fail(id, &err);
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    // Echo replies, handed back with the identifier of the client
+    pub(crate) fn icmp_ready(&mut self, id: usize) {
+        let mut buf = mem::take(&mut self.buf);
+        while let Some(Upstream::Icmp(socket)) = self.router.flow(id).map(|flow| &flow.upstream) {
+            match socket.recv(&mut buf) {
+                Ok((start, n)) => {
+                    let reply = &mut buf[start..n];
+                    flow_log!(self, Trace, Icmp, id, "recv {} bytes{}", reply.len(), Hex(reply));
+                    if reply.len() < 8 || reply[0] != ECHO_REPLY {
+                        continue;
+                    }
+                    let flow = match self.router.flow(id) {
+                        Some(flow) => flow,
+                        None => break,
+                    };
+                    let Some((client_id, _)) = flow.datagram.payload.echo() else {
+                        break;
+                    };
+                    icmp::set_echo_id(reply, client_id);
+                    flow.bytes_down += reply.len() as u64;
+                    flow.packets_down += 1;
+                    let pkt = flow.datagram.icmp_resp_pack(reply);
+                    self.stats.bytes_down(reply.len());
+                    self.respond(&pkt);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => match self.relay_errors(id) {
+                    Some(false) => continue,
+                    Some(true) => {
+                        self.close(id, None);
+                        break;
+                    }
+                    None => {
+                        flow_log!(self, Info, Icmp, id, "recv error: {err}");
+                        self.fail(id, &err);
+                        break;
+                    }
+                },
+            }
+        }
+        self.buf = buf;
+    }
+
+    // Reply to the echo request right away, without the remote ever seeing it
+    fn echo_locally(&mut self, key: FlowKey, datagram: &Datagram) {
+        log!(self.logging, Trace, Icmp, tuple = key; "echo reply made up locally");
+        let reply = datagram.icmp_resp_pack(&datagram.payload.pack(&[], &[]));
+        self.respond(&reply);
+    }
+}
+
+// ICMP sockets are off limits to this process, retrying is of no use
+fn not_permitted(err: &Error) -> bool {
+    err.kind() == ErrorKind::PermissionDenied
+        || matches!(err.raw_os_error(), Some(libc::EPROTONOSUPPORT) | Some(libc::EAFNOSUPPORT) | Some(libc::ESOCKTNOSUPPORT))
+}
@@ -1,2 +1,4 @@
 pub mod tcp;
 pub mod udp;
+pub mod icmp;
+pub mod hops;
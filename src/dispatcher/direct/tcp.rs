@@ -1,90 +1,307 @@
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::Shutdown;
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
-use crate::log;
-use crate::protocol::internet::tcp::{ACK, PSH_ACK, RST, SYN_ACK};
-use crate::thread_pool::event::Event::{IDLE, LOG, MESSAGE, TCP};
-use crate::thread_pool::event::TcpState;
-use crate::thread_pool::handler::Handler;
-
-impl Handler {
-    pub fn handle_tcp(&mut self) {
-        let id = self.id;
-        let payload = if let Some(pkt) = &self.payload {
-            pkt
-        } else {
-            return;
+
+use mio::net::TcpStream;
+
+use crate::dispatcher::{Dispatcher, Reply, refusal, reset};
+use crate::dispatcher::flow::{Flow, Route, State, TcpState, Upstream};
+use crate::dispatcher::socks5::tcp_based::Client;
+use crate::logging::{log, Hex};
+use crate::protocol::internet::Datagram;
+use crate::protocol::internet::view::FlowKey;
+use crate::stats::Protocol;
+use crate::protocol::internet::tcp::{ACK, FIN, FIN_ACK, PSH_ACK, RST, SYN, SYN_ACK};
+
+// Client data buffered for an upstream slower than the client, segments beyond it are dropped
+// unacknowledged until the upstream takes some
+const MAX_PENDING: usize = 256 * 1024;
+
+impl Dispatcher {
+    pub(crate) fn open_tcp(&mut self, id: usize, key: FlowKey, datagram: Datagram) {
+        let dst_addr = datagram.payload.dst_addr();
+        log!(self.logging, Trace, Tcp, flow = id, tuple = key; "{}", datagram.payload.info());
+        self.stats.connect_attempt();
+
+        let connected = match &self.proxy {
+            Some(proxy) => {
+                log!(self.logging, Debug, Tcp, flow = id, tuple = key; "connect to {dst_addr} through {}", proxy.addr);
+                Client::connect(proxy.addr, dst_addr, proxy.auth.clone()).map(Upstream::Socks5)
+            }
+            None => {
+                log!(self.logging, Debug, Tcp, flow = id, tuple = key; "connect to {dst_addr}");
+                TcpStream::connect(dst_addr).map(Upstream::Tcp)
+            }
         };
-        let data = payload.payload();
-        self.report(log!("{}", payload.info()));
-        
-        if let Some(stream) = &mut self.tcp {
-            match stream.write_all(data) {
-                Ok(_) => {
-                    self.report(LOG("Send to remote success".into()));
-                    self.report(MESSAGE(*ACK, vec![]));
+        let mut upstream = match connected {
+            Ok(upstream) => upstream,
+            Err(err) => {
+                log!(self.logging, Info, Tcp, flow = id, tuple = key; "failed connect: {err}");
+                self.stats.connect_failure();
+                if let Some(pkt) = refusal(&datagram, &err) {
+                    self.respond(&pkt);
                 }
-                Err(e) => {
-                    self.report(log!("Send data error: bye bye, {:?}", e));
-                    self.report(MESSAGE(*RST, vec![]));
-                    self.report(IDLE);
+                return;
+            }
+        };
+
+        if let Err(err) = self.register(id, &mut upstream) {
+            log!(self.logging, Error, Tcp, flow = id, tuple = key; "register error: {err}");
+            self.stats.connect_failure();
+            if let Some(pkt) = reset(&datagram) {
+                self.respond(&pkt);
+            }
+            return;
+        }
+
+        // The SYN takes one sequence number
+        let rcv_nxt = datagram.payload.seq().map(|seq| seq.wrapping_add(1));
+        let mut flow = Flow::new(id, key, State::TCP(TcpState::Connecting), datagram, upstream);
+        flow.rcv_nxt = rcv_nxt;
+        if let Some(proxy) = &self.proxy {
+            flow.route = Route::Proxy;
+            flow.upstream_addr = proxy.addr;
+        }
+        flow.domain = self.domain(dst_addr);
+        self.router.insert(flow);
+        self.stats.flow_opened(Protocol::Tcp);
+    }
+
+    // Segment from the client of an existing flow
+    pub(crate) fn handle_tcp(&mut self, id: usize) {
+        let (payload, state) = match self.router.flow(id) {
+            Some(flow) => (Arc::clone(&flow.datagram.payload), flow.state),
+            None => return,
+        };
+        let flags = *payload.flags_type();
+        flow_log!(self, Trace, Tcp, id, "{}", payload.info());
+
+        if flags & *RST != 0 {
+            self.close(id, None);
+            return;
+        }
+
+        match state {
+            // SYN retransmission, answered once connected
+            State::TCP(TcpState::Connecting) => return,
+            // The SYN-ACK was lost, it goes out again with the sequence number it had
+            State::TCP(TcpState::SynAckWait) if flags & *SYN != 0 => {
+                if let Some(flow) = self.router.flow(id) {
+                    flow.snd_nxt = flow.snd_nxt.map(|seq| seq.wrapping_sub(1));
                 }
+                self.reply(id, Reply::Segment(*SYN_ACK, &[]));
+                return;
+            }
+            State::TCP(TcpState::SynAckWait) if flags & *ACK != 0 => {
+                self.set_state(id, TcpState::Communication);
+            }
+            _ => {}
+        }
+
+        let data = payload.payload();
+        let fin = flags & *FIN != 0;
+        let (seq, rcv_nxt, full) = match (payload.seq(), self.router.flow(id)) {
+            (Some(seq), Some(flow)) => (seq, flow.rcv_nxt.unwrap_or(seq), flow.pending.len() >= MAX_PENDING),
+            _ => return,
+        };
+        // Bytes of the segment already taken in, a retransmission is not sent upstream twice
+        let taken = rcv_nxt.wrapping_sub(seq) as i32;
+        if taken < 0 {
+            flow_log!(self, Trace, Tcp, id, "seq({seq}) after a gap, expected({rcv_nxt}), drop");
+            // A duplicate ACK has the client send the missing data again, unless it is held back
+            if !full {
+                self.reply(id, Reply::Segment(*ACK, &[]));
             }
             return;
         }
+        let new = data.get(taken as usize..).unwrap_or_default();
+        if !new.is_empty() && full {
+            flow_log!(self, Debug, Tcp, id, "{} bytes pending, drop {} until the upstream takes some", MAX_PENDING, new.len());
+            if let Some(flow) = self.router.flow(id) {
+                flow.throttled = true;
+            }
+            return;
+        }
+        // A FIN right after the data, not one acknowledged before
+        let new_fin = fin && taken as usize <= data.len();
+        if let Some(flow) = self.router.flow(id) {
+            flow.rcv_nxt = Some(rcv_nxt.wrapping_add(new.len() as u32 + u32::from(new_fin)));
+        }
 
-        // Connect
-        let dst_addr = payload.dst_addr();
-        self.report(log!("connect to {addr}", addr = dst_addr));
-        let stream = match TcpStream::connect_timeout(&dst_addr, Duration::from_secs(5)) {
-            Ok(stream) => {
-                self.report(log!("success connect to server"));
-                stream
+        if !new.is_empty() {
+            if let Err(err) = self.send_tcp(id, new) {
+                flow_log!(self, Info, Tcp, id, "send error: {err}");
+                self.close(id, Some(*RST));
+                return;
             }
-            Err(err) => {
-                self.report(log!("failed connect: {e:#?}", e = err));
-                self.report(MESSAGE(*RST, vec![]));
-                self.report(IDLE);
+            flow_log!(self, Trace, Tcp, id, "sent {} bytes", new.len());
+        }
+
+        if new_fin {
+            self.reply(id, Reply::Segment(*ACK, &[]));
+            if state == State::TCP(TcpState::Closing) {
+                // Both sides are done
+                self.close(id, None);
                 return;
             }
+            self.set_state(id, TcpState::FinWait);
+            // Half close the upstream once pending data is out
+            if let Err(err) = self.send_tcp(id, &[]) {
+                flow_log!(self, Info, Tcp, id, "send error: {err}");
+                self.close(id, Some(*RST));
+            }
+        } else if !data.is_empty() || fin {
+            // Retransmissions are acknowledged again, the client may have missed the first ACK
+            self.reply(id, Reply::Segment(*ACK, &[]));
+        }
+    }
+
+    // Readiness of the upstream connection
+    pub(crate) fn tcp_ready(&mut self, id: usize, readable: bool, writable: bool) {
+        let flow = match self.router.flow(id) {
+            Some(flow) => flow,
+            None => return,
         };
+        if flow.state == State::TCP(TcpState::Connecting) {
+            let ready = match &mut flow.upstream {
+                Upstream::Tcp(stream) => connected(stream),
+                Upstream::Socks5(client) => client.drive(),
+                Upstream::Udp(_) | Upstream::Icmp(_) => return,
+            };
+            match ready {
+                Ok(true) => {
+                    flow.state = State::TCP(TcpState::SynAckWait);
+                    flow_log!(self, Debug, Tcp, id, "connected");
+                    self.reply(id, Reply::Segment(*SYN_ACK, &[]));
+                }
+                Ok(false) => return,
+                Err(err) => {
+                    flow_log!(self, Info, Tcp, id, "failed connect: {err}");
+                    self.stats.connect_failure();
+                    self.fail(id, &err);
+                    return;
+                }
+            }
+        }
 
-        // Send S.
-        self.report(MESSAGE(*SYN_ACK, vec![]));
-        self.report(TCP(TcpState::SynAckWait));
-
-        let reporter = Arc::clone(&self.reporter);
-
-        let mut stream_cloned = stream.try_clone().unwrap();
-        self.tcp = Some(stream);
-
-        // Receive message
-        let mut buf = vec![0; 1500];
-        let job = thread::spawn(move || {
-            log!("tcp loop start").report(id, &reporter);
-            loop {
-                match stream_cloned.read(&mut buf) {
-                    Ok(n) => {
-                        if n == 0 {
-                            log!("reach end").report(id, &reporter);
-                            MESSAGE(*RST, vec![]).report(id, &reporter);
-                            break;
-                        }
-                        log!("<<---recv {n} bytes\n\t{:?}", &buf[..n]).report(id, &reporter);
-                        MESSAGE(*PSH_ACK, buf[..n].to_vec()).report(id, &reporter);
-                    }
-                    Err(e) => {
-                        log!("{:#?}", e).report(id, &reporter);
-                        MESSAGE(*RST, vec![]).report(id, &reporter);
-                        break;
+        if writable {
+            if let Err(err) = self.send_tcp(id, &[]) {
+                flow_log!(self, Info, Tcp, id, "send error: {err}");
+                self.close(id, Some(*RST));
+                return;
+            }
+            // Room again for what was dropped, the ACK tells the client where to go on from
+            let resumed = self.router.flow(id).is_some_and(|flow| {
+                let resumed = flow.throttled && flow.pending.len() < MAX_PENDING;
+                flow.throttled &= !resumed;
+                resumed
+            });
+            if resumed {
+                self.reply(id, Reply::Segment(*ACK, &[]));
+            }
+        }
+
+        if readable {
+            self.recv_tcp(id);
+        }
+    }
+
+    // Write `data` after whatever is still pending
+    fn send_tcp(&mut self, id: usize, data: &[u8]) -> Result<()> {
+        let flow = match self.router.flow(id) {
+            Some(flow) => flow,
+            None => return Ok(()),
+        };
+        let stream = match flow.upstream.tcp_stream() {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+
+        flow.pending.extend_from_slice(data);
+        while !flow.pending.is_empty() {
+            match stream.write(&flow.pending) {
+                Ok(n) => {
+                    flow.pending.drain(..n);
+                    flow.bytes_up += n as u64;
+                    self.stats.bytes_up(n);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        if flow.pending.is_empty() && flow.state == State::TCP(TcpState::FinWait) {
+            stream.shutdown(Shutdown::Write).unwrap_or(());
+        }
+        Ok(())
+    }
+
+    fn recv_tcp(&mut self, id: usize) {
+        let mut buf = std::mem::take(&mut self.buf);
+        let segment_size = self.segment_size();
+        while let Some(stream) = self.router.flow(id).and_then(|flow| flow.upstream.tcp_stream()) {
+            match stream.read(&mut buf[..segment_size]) {
+                Ok(0) => {
+                    flow_log!(self, Debug, Tcp, id, "remote closed");
+                    self.recv_fin(id);
+                    break;
+                }
+                Ok(n) => {
+                    if let Some(flow) = self.router.touch(id) {
+                        flow.bytes_down += n as u64;
                     }
+                    self.stats.bytes_down(n);
+                    flow_log!(self, Trace, Tcp, id, "recv {n} bytes{}", Hex(&buf[..n]));
+                    self.reply(id, Reply::Segment(*PSH_ACK, &buf[..n]));
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    flow_log!(self, Info, Tcp, id, "recv error: {err}");
+                    self.close(id, Some(*RST));
+                    break;
                 }
             }
-            IDLE.report(id, &reporter);
-        });
+        }
+        self.buf = buf;
+    }
+
+    // The remote finished sending, pass its FIN on to the client
+    fn recv_fin(&mut self, id: usize) {
+        let state = match self.router.flow(id) {
+            Some(flow) => flow.state,
+            None => return,
+        };
+
+        match state {
+            State::TCP(TcpState::FinWait) => {
+                self.reply(id, Reply::Segment(*FIN_ACK, &[]));
+                self.close(id, None);
+            }
+            State::TCP(TcpState::Closing) => {}
+            _ => {
+                self.reply(id, Reply::Segment(*FIN_ACK, &[]));
+                self.set_state(id, TcpState::Closing);
+            }
+        }
+    }
 
-        self.job = Some(job);
+    fn set_state(&mut self, id: usize, state: TcpState) {
+        if let Some(flow) = self.router.flow(id) {
+            flow.state = State::TCP(state);
+        }
+    }
+}
+
+// Whether a non-blocking connect has completed
+fn connected(stream: &TcpStream) -> Result<bool> {
+    if let Some(err) = stream.take_error()? {
+        return Err(err);
+    }
+    match stream.peer_addr() {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotConnected => Ok(false),
+        Err(err) => Err(err),
     }
-}
\ No newline at end of file
+}
@@ -1,96 +1,186 @@
-use std::net::UdpSocket;
-use std::sync::Arc;
-use std::thread;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::AsRawFd;
 
-use crate::log;
-use crate::thread_pool::event::Event::{IDLE, MESSAGE};
-use crate::thread_pool::handler::Handler;
+use mio::net::UdpSocket;
 
-impl Handler {
-    pub fn handle_udp(&mut self) {
-        let payload = if let Some(payload) = &self.payload {
-            payload
-        } else {
-            return;
-        };
-        let data = payload.payload();
-
-        if let Some(udp) = &self.udp {
-            match udp.send(data) {
-                Ok(n) => {
-                    self.report(log!("sent {n} bytes"));
-                }
-                Err(err) => {
-                    self.report(log!("sent error: {:#?}", err));
-                    self.report(IDLE);
-                }
-            }
-
-            return;
-        }
+use crate::config::DnsMode;
+use crate::dispatcher::{Dispatcher, Reply, refusal, DNS_PORT};
+use crate::dispatcher::direct::hops;
+use crate::dispatcher::flow::{upstream_ttl, Flow, State, UdpState, Upstream};
+use crate::dns::message;
+use crate::logging::{log, Hex};
+use crate::protocol::internet::Datagram;
+use crate::protocol::internet::view::FlowKey;
+use crate::stats::Protocol;
 
-        let udp = match UdpSocket::bind("0.0.0.0:0") {
-            Ok(udp_socket) => {
-                self.report(log!("bind success"));
-                udp_socket
-            }
+impl Dispatcher {
+    // A session of the client's address with an upstream socket of its own. The socket is not connected,
+    // the session sends to and takes datagrams from any remote (see `Router`)
+    pub(crate) fn open_udp(&mut self, id: usize, key: FlowKey, datagram: Datagram) {
+        self.stats.connect_attempt();
+        let dst_addr = datagram.payload.dst_addr();
+        let upstream_addr = match self.dns {
+            DnsMode::Server(server) if dst_addr.port() == DNS_PORT => server,
+            _ => dst_addr,
+        };
+        let any = match upstream_addr {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let socket = match UdpSocket::bind(any).and_then(|socket| hops::enable(socket.as_raw_fd()).map(|()| socket)) {
+            Ok(socket) => socket,
             Err(err) => {
-                self.report(log!("bind failed: {:#?}", err));
+                log!(self.logging, Error, Udp, flow = id, tuple = key; "bind failed: {err}");
+                self.stats.connect_failure();
                 return;
             }
         };
 
-        let id = self.id;
-        let dst_addr = payload.dst_addr();
-        let reporter = Arc::clone(&self.reporter);
-
-        match udp.connect(dst_addr) {
-            Ok(_) => {
-                self.report(log!("connect to server success"))
-            }
+        let ttl = upstream_ttl(datagram.header.ttl);
+        let ttl_set = match upstream_addr {
+            SocketAddr::V4(_) => socket.set_ttl(ttl.into()),
+            SocketAddr::V6(_) => Ok(()),
+        };
+        let sent = match ttl_set.and_then(|()| socket.send_to(datagram.payload.payload(), upstream_addr)) {
+            Ok(n) => n,
             Err(err) => {
-                self.report(log!("udp connect to server error: {:#?}", err));
-                self.report(IDLE);
+                log!(self.logging, Info, Udp, flow = id, tuple = key; "send error: {err}");
+                self.stats.connect_failure();
+                if let Some(pkt) = refusal(&datagram, &err) {
+                    self.respond(&pkt);
+                }
+                return;
             }
+        };
+        if dst_addr.port() == DNS_PORT {
+            log!(self.logging, Debug, Dns, flow = id, tuple = key; "query, {sent} bytes");
+            self.stats.dns_query();
+        } else {
+            log!(self.logging, Trace, Udp, flow = id, tuple = key; "sent {sent} bytes");
         }
+        self.stats.bytes_up(sent);
 
-        match udp.send(data) {
-            Ok(n) => {
-                self.report(log!("sent {n} bytes"));
-            }
-            Err(err) => {
-                self.report(log!("sent error: {:#?}", err));
-                self.report(IDLE);
-                return;
-            }
+        let mut upstream = Upstream::Udp(socket);
+        if let Err(err) = self.register(id, &mut upstream) {
+            log!(self.logging, Error, Udp, flow = id, tuple = key; "register error: {err}");
+            self.stats.connect_failure();
+            return;
         }
 
-        let udp_cloned = udp.try_clone().unwrap();
-        self.udp = Some(udp);
+        let mut flow = Flow::new(id, key, State::UDP(UdpState::Communication), datagram, upstream);
+        flow.ttl = ttl;
+        flow.bytes_up = sent as u64;
+        flow.upstream_addr = upstream_addr;
+        flow.domain = self.domain(dst_addr);
+        self.router.insert(flow);
+        self.stats.flow_opened(Protocol::Udp);
+    }
 
-        // Receive message
-        let mut buf = vec![0; 1500];
-        let job = thread::spawn(move || {
-            loop {
-                log!("udp loop start").report(id, &reporter);
+    // Datagram from the client of an existing flow to `dst`, `data` borrowed from wherever it was read into
+    pub(crate) fn handle_udp(&mut self, id: usize, ttl: u8, dst: SocketAddr, data: &[u8]) {
+        let mut relayed = false;
+        loop {
+            let flow = match self.router.flow(id) {
+                Some(flow) => flow,
+                None => return,
+            };
+            let dns = flow.datagram.payload.dst_addr().port() == DNS_PORT;
+            let to = flow.outbound(dst);
+            let sent = flow.set_ttl(ttl).and_then(|()| match &flow.upstream {
+                Upstream::Udp(socket) => socket.send_to(data, to),
+                _ => Ok(0),
+            });
 
-                match udp_cloned.recv(&mut buf) {
-                    Ok(n) => {
-                        // match udp.recv_from(&mut buf) {
-                        //     Ok((n, addr)) => {
-                        log!("udp recv {n} bytes, content: {}", String::from_utf8_lossy(&buf[..n])).report(id, &reporter);
-                        MESSAGE(0, buf[..n].to_vec()).report(id, &reporter);
+            match sent {
+                Ok(n) => {
+                    flow.bytes_up += n as u64;
+                    self.stats.bytes_up(n);
+                    if dns {
+                        self.stats.dns_query();
+                    }
+                    flow_log!(self, Trace, Udp, id, "sent {n} bytes");
+                    return;
+                }
+                // An error about an earlier datagram fails the send, relayed it goes again
+                // A session lives on when one of its remotes is unreachable, a DNS flow has only the one
+                Err(err) => match (relayed, self.relay_errors(id)) {
+                    (_, Some(true)) if dns => {
+                        self.close(id, None);
+                        return;
+                    }
+                    (false, Some(_)) => relayed = true,
+                    _ => {
+                        flow_log!(self, Info, Udp, id, "send error: {err}");
+                        self.fail(id, &err);
+                        return;
                     }
-                    Err(err) => {
-                        log!("udp recv error: {:#?}", err).report(id, &reporter);
-                        IDLE.report(id, &reporter);
+                },
+            }
+        }
+    }
+
+    pub(crate) fn udp_ready(&mut self, id: usize) {
+        let dns = self.router.flow(id).is_some_and(|flow| flow.datagram.payload.dst_addr().port() == DNS_PORT);
+        let mut buf = std::mem::take(&mut self.buf);
+        while let Some(Upstream::Udp(socket)) = self.router.flow(id).map(|flow| &flow.upstream) {
+            match socket.recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    let Some(flow) = self.router.flow(id) else {
                         break;
+                    };
+                    // A DNS flow takes answers from the resolver it asked only
+                    if dns && from != flow.upstream_addr {
+                        flow_log!(self, Debug, Dns, id, "dropped {n} bytes from {from}");
+                        continue;
                     }
+                    let SocketAddr::V4(src) = flow.inbound(from) else {
+                        continue;
+                    };
+                    flow.bytes_down += n as u64;
+                    self.stats.bytes_down(n);
+                    flow_log!(self, Trace, Udp, id, "recv {n} bytes from {src}{}", Hex(&buf[..n]));
+                    if dns {
+                        self.learn(id, &buf[..n]);
+                    }
+                    self.reply(id, Reply::Datagram(src, &buf[..n]));
                 }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => match self.relay_errors(id) {
+                    Some(true) if dns => {
+                        self.close(id, None);
+                        break;
+                    }
+                    Some(_) => continue,
+                    // Errors about unconnected sockets are not reported where they are not queued
+                    None => {
+                        flow_log!(self, Info, Udp, id, "recv error: {err}");
+                        self.fail(id, &err);
+                        break;
+                    }
+                },
             }
-            log!("udp recv end").report(id, &reporter);
-        });
+        }
+        self.buf = buf;
+    }
 
-        self.job = Some(job);
+    // Remember the names answered by a DNS response
+    fn learn(&mut self, id: usize, response: &[u8]) {
+        if let Ok(answer) = message::parse_response(response) {
+            flow_log!(self, Debug, Dns, id, "{} => {:?}", answer.name, answer.addrs);
+            self.names.insert(answer);
+            self.stats.dns_cache_entries(self.names.len());
+        }
+    }
+
+    // Name `addr` was resolved from, if a DNS response passed through for it
+    pub(crate) fn domain(&mut self, addr: SocketAddr) -> Option<String> {
+        let SocketAddr::V4(addr) = addr else {
+            return None;
+        };
+        let domain = self.names.lookup(*addr.ip());
+        self.stats.dns_cache_lookup(domain.is_some());
+        domain
     }
-}
\ No newline at end of file
+}
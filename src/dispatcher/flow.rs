@@ -0,0 +1,265 @@
+use std::io::Result;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpStream, UdpSocket};
+
+use crate::dispatcher::direct::icmp::IcmpSocket;
+use crate::dispatcher::socks5::tcp_based::Client;
+use crate::protocol::internet::Datagram;
+use crate::protocol::internet::tcp::{self, FIN, SYN};
+use crate::protocol::internet::view::FlowKey;
+use crate::stats::Protocol;
+use crate::util::json_string;
+
+// One TCP connection, UDP session or ICMP echo session between a client behind the interface and its remote
+pub struct Flow {
+    pub id: usize,
+    pub key: FlowKey,
+    // `key` as logged
+    pub name: String,
+    pub state: State,
+    // Latest datagram from the client, replies are built from it
+    pub datagram: Datagram,
+    pub upstream: Upstream,
+    pub route: Route,
+    // Where the first datagram went, the SOCKS5 server of a proxied connection
+    pub upstream_addr: SocketAddr,
+    // Name the destination was resolved from, learned from DNS responses
+    pub domain: Option<String>,
+    // Client data not yet accepted by the upstream socket
+    pub pending: Vec<u8>,
+    // Client data was dropped unacknowledged while `pending` was full, acknowledged again once it drains
+    pub throttled: bool,
+    // Time to live of the upstream datagrams, 0 until set
+    pub ttl: u8,
+    // Sequence number of the next segment to the client, set by the first one
+    pub snd_nxt: Option<u32>,
+    // Next sequence number expected from the client, everything before it was acknowledged
+    pub rcv_nxt: Option<u32>,
+    pub created: Instant,
+    pub last_active: Instant,
+    // Counted like the global `Stats`
+    pub packets_up: u64,
+    pub packets_down: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+pub enum Upstream {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    // TCP relayed by the SOCKS5 proxy, carries the payload once the handshake is done
+    Socks5(Client),
+    // Echo requests of one identifier, sent from an unprivileged ICMP socket
+    Icmp(IcmpSocket),
+}
+
+impl Upstream {
+    // Stream the TCP payload goes over, to the remote or to the proxy
+    pub fn tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        match self {
+            Upstream::Tcp(stream) => Some(stream),
+            Upstream::Socks5(client) => Some(client.stream()),
+            Upstream::Udp(_) | Upstream::Icmp(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Route {
+    /// Straight to the destination.
+    Direct,
+    /// Through the SOCKS5 proxy.
+    Proxy,
+}
+
+/// What a debugging UI shows about one flow, see `tun::Handle::flows`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowInfo {
+    pub id: usize,
+    pub protocol: Protocol,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    /// `None` for UDP and ICMP.
+    pub tcp_state: Option<TcpState>,
+    pub route: Route,
+    pub upstream: SocketAddr,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub packets_up: u64,
+    pub packets_down: u64,
+    pub age: Duration,
+    pub idle: Duration,
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    TCP(TcpState),
+    UDP(UdpState),
+    ICMP(IcmpState),
+}
+
+impl State {
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            State::TCP(_) => Protocol::Tcp,
+            State::UDP(_) => Protocol::Udp,
+            State::ICMP(_) => Protocol::Icmp,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TcpState {
+    Connecting,
+    SynAckWait,
+    Communication,
+    // The client sent its FIN
+    FinWait,
+    // The remote closed, FIN sent to the client
+    Closing,
+    RstWait,
+    Destroy,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UdpState {
+    Communication,
+    Destroy,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IcmpState {
+    Communication,
+    Destroy,
+}
+
+impl Flow {
+    pub fn new(id: usize, key: FlowKey, state: State, datagram: Datagram, upstream: Upstream) -> Self {
+        let now = Instant::now();
+        let upstream_addr = datagram.payload.dst_addr();
+        Self {
+            id,
+            key,
+            name: key.to_string(),
+            state,
+            datagram,
+            upstream,
+            route: Route::Direct,
+            upstream_addr,
+            domain: None,
+            pending: Vec::new(),
+            throttled: false,
+            ttl: 0,
+            snd_nxt: None,
+            rcv_nxt: None,
+            created: now,
+            last_active: now,
+            packets_up: 1,
+            packets_down: 0,
+            bytes_up: 0,
+            bytes_down: 0,
+        }
+    }
+
+    // TCP segment to the client, numbered on from the ones before it
+    pub fn segment(&mut self, flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = self.datagram.payload.pack(&[flags], data);
+        let seq = match self.snd_nxt {
+            Some(seq) => {
+                tcp::set_seq(&mut segment, seq);
+                seq
+            }
+            None => tcp::seq(&segment),
+        };
+        // SYN and FIN occupy one sequence number each
+        let len = data.len() as u32 + u32::from(flags & (*SYN | *FIN) != 0);
+        self.snd_nxt = Some(seq.wrapping_add(len));
+        // Only what was taken in is acknowledged, not whatever the latest segment carried
+        if let Some(ack) = self.rcv_nxt {
+            tcp::set_ack(&mut segment, ack);
+        }
+        self.datagram.resp_pack(&segment)
+    }
+
+    // Upstream datagrams go on with the time to live of the client's, see `upstream_ttl`. The hop
+    // limit of an IPv6 upstream is left to the system
+    pub fn set_ttl(&mut self, ttl: u8) -> Result<()> {
+        let ttl = upstream_ttl(ttl);
+        if ttl == self.ttl || self.upstream_addr.is_ipv6() {
+            return Ok(());
+        }
+        match &self.upstream {
+            Upstream::Udp(socket) => socket.set_ttl(ttl.into())?,
+            Upstream::Icmp(socket) => socket.set_ttl(ttl)?,
+            Upstream::Tcp(_) | Upstream::Socks5(_) => return Ok(()),
+        }
+        self.ttl = ttl;
+        Ok(())
+    }
+
+    // Where a datagram of the client to `dst` goes: where the first one went for the same destination,
+    // a DNS query to the resolver of `DnsMode::Server`, anywhere else as addressed
+    pub fn outbound(&self, dst: SocketAddr) -> SocketAddr {
+        if dst == self.key.dst { self.upstream_addr } else { dst }
+    }
+
+    // Who a datagram from `src` is from to the client, the reverse of `outbound`
+    pub fn inbound(&self, src: SocketAddr) -> SocketAddr {
+        if src == self.upstream_addr { self.key.dst } else { src }
+    }
+
+    pub fn info(&self, now: Instant) -> FlowInfo {
+        FlowInfo {
+            id: self.id,
+            protocol: self.state.protocol(),
+            src: self.key.src,
+            dst: self.key.dst,
+            tcp_state: match self.state {
+                State::TCP(state) => Some(state),
+                State::UDP(_) | State::ICMP(_) => None,
+            },
+            route: self.route,
+            upstream: self.upstream_addr,
+            bytes_up: self.bytes_up,
+            bytes_down: self.bytes_down,
+            packets_up: self.packets_up,
+            packets_down: self.packets_down,
+            age: now.duration_since(self.created),
+            idle: now.duration_since(self.last_active),
+            domain: self.domain.clone(),
+        }
+    }
+}
+
+impl FlowInfo {
+    /// One JSON object, durations in milliseconds.
+    pub fn json(&self) -> String {
+        let mut json = format!(r#"{{"id":{},"protocol":"{}","src":"{}","dst":"{}","tcp_state":"#,
+                               self.id, self.protocol.name(), self.src, self.dst);
+        match self.tcp_state {
+            Some(state) => json_string(&mut json, &format!("{state:?}")),
+            None => json.push_str("null"),
+        }
+        let route = match self.route {
+            Route::Direct => "direct",
+            Route::Proxy => "proxy",
+        };
+        json.push_str(&format!(r#","route":"{route}","upstream":"{}","bytes_up":{},"bytes_down":{},"packets_up":{},"packets_down":{},"age_ms":{},"idle_ms":{},"domain":"#,
+                               self.upstream, self.bytes_up, self.bytes_down, self.packets_up, self.packets_down,
+                               self.age.as_millis(), self.idle.as_millis()));
+        match &self.domain {
+            Some(domain) => json_string(&mut json, domain),
+            None => json.push_str("null"),
+        }
+        json.push('}');
+        json
+    }
+}
+
+// What a datagram with `ttl` has left past the interface, the hop it was received at
+pub fn upstream_ttl(ttl: u8) -> u8 {
+    ttl.saturating_sub(1).max(1)
+}
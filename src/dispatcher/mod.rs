@@ -1,21 +1,786 @@
 use std::fs::File;
-use std::io::Write;
-use std::net::IpAddr;
-use std::thread;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, IntoRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::unix::SourceFd;
+
+use crate::capture::{Capture, Direction};
+use crate::config::{Config, DnsMode, OverflowPolicy, Proxy, Timeouts};
+use crate::device::batch::{Batch, BATCH};
+use crate::device::{vnet, PacketFraming};
+use crate::dns::cache::Cache;
+use crate::dispatcher::flow::{FlowInfo, State, TcpState, Upstream};
+use crate::dispatcher::router::Router;
 use crate::dispatcher::simulator::Simulator;
-use crate::logging::Logging;
+use crate::logging::{log, Hex, Logging};
 use crate::protocol::internet::{Datagram, Protocol, Packet, PseudoHeader};
+use crate::protocol::ParseError;
+use crate::protocol::internet::icmp;
 use crate::protocol::internet::icmp::Icmp;
-use crate::protocol::internet::tcp::Tcp;
+use crate::protocol::internet::tcp::{FIN, RST, RST_ACK, SYN, Tcp};
 use crate::protocol::internet::udp::Udp;
+use crate::protocol::internet::view::{FlowKey, Ipv4};
+use crate::stats::{DropReason, Stats};
 use crate::util::{bytes_to_u32, bytes_to_u32_no_prefix};
+use crate::util::pool::Pool;
+
+// `log!` about flow `id`, with its id and 5-tuple as fields. Defined ahead of the submodules using it
+macro_rules! flow_log {
+    ($dispatcher:expr, $level:ident, $module:ident, $id:expr, $($arg:tt)+) => {{
+        let id: usize = $id;
+        let name = $dispatcher.router.name(id);
+        $crate::logging::log!($dispatcher.logging, $level, $module, flow = id, tuple = name; $($arg)+);
+    }};
+}
 
 pub mod simulator;
 pub mod direct;
 pub mod socks5;
+pub mod flow;
+mod router;
+
+const MTU: usize = 1500;
+// Largest segment payload fitting the MTU
+const MSS: usize = 1460;
+// Data read from an upstream TCP connection at once, at most a super-packet of an IFF_VNET_HDR device
+const MAX_SEGMENT: usize = vnet::MAX_PACKET - 60 - 60;
+const INTERFACE: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_FLOW: usize = 2;
+// Wake up at least this often to expire flows
+const TICK: Duration = Duration::from_secs(1);
+const DNS_PORT: u16 = 53;
+// Batches read from the interface before the upstream sockets get their turn
+const INTERFACE_BUDGET: usize = 8;
+
+#[cfg(not(feature = "mobile"))]
+const EVENTS_CAPACITY: usize = 1024;
+#[cfg(feature = "mobile")]
+const EVENTS_CAPACITY: usize = 128;
+
+#[cfg(not(feature = "mobile"))]
+const DNS_CACHE_CAPACITY: usize = 4096;
+#[cfg(feature = "mobile")]
+const DNS_CACHE_CAPACITY: usize = 512;
+
+// Single threaded event loop multiplexing the interface and every upstream socket
+pub struct Dispatcher {
+    poll: Poll,
+    interface: File,
+    logging: Logging,
+    router: Router,
+    next_id: usize,
+    id_step: usize,
+    max_flows: usize,
+    policy: OverflowPolicy,
+    timeouts: Timeouts,
+    proxy: Option<Proxy>,
+    dns: DnsMode,
+    last_sweep: Instant,
+    // The interface was left readable once its budget ran out
+    interface_pending: bool,
+    stopper: Stopper,
+    stats: Arc<Stats>,
+    inspector: Inspector,
+    inspections: mpsc::Receiver<mpsc::Sender<Vec<FlowInfo>>>,
+    names: Cache,
+    capture: Option<Arc<Mutex<Capture>>>,
+    framing: PacketFraming,
+    verify_checksums: bool,
+    gateway: Option<Ipv4Addr>,
+    // Echo requests go out of ICMP sockets until one is refused, then get local replies
+    icmp_sockets: bool,
+    batch: Batch,
+    // Read and write buffers of the interface
+    pool: Pool,
+    // Responses written together once the packets read at once are handled
+    outgoing: Vec<Frame>,
+    buf: Vec<u8>,
+}
+
+// A packet framed for the interface in a buffer of the pool
+struct Frame {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl AsRef<[u8]> for Frame {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+// Asks a running dispatcher to stop and waits for it to be done, from any thread
+#[derive(Clone)]
+pub struct Stopper {
+    stopping: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+    done: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Stopper {
+    // Signal the event loop, then wait until it has closed every flow or `deadline` passed
+    pub fn stop(&self, deadline: Duration) -> Result<()> {
+        self.signal()?;
+        self.wait(deadline)
+    }
+
+    // Tell the event loop to close every flow and end
+    pub fn signal(&self) -> Result<()> {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.waker.wake()
+    }
+
+    // Wait until the event loop has ended or `deadline` passed
+    pub fn wait(&self, deadline: Duration) -> Result<()> {
+        let (lock, cvar) = &*self.done;
+        let done = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (done, _) = cvar.wait_timeout_while(done, deadline, |done| !*done)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *done {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::TimedOut, "dispatcher did not stop in time"))
+        }
+    }
+
+    fn finish(&self) {
+        let (lock, cvar) = &*self.done;
+        *lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+        cvar.notify_all();
+    }
+}
+
+// Asks a running dispatcher for a snapshot of its flows, from any thread
+#[derive(Clone)]
+pub struct Inspector {
+    requests: mpsc::Sender<mpsc::Sender<Vec<FlowInfo>>>,
+    waker: Arc<Waker>,
+}
+
+impl Inspector {
+    pub fn flows(&self, deadline: Duration) -> Result<Vec<FlowInfo>> {
+        let not_running = || Error::new(ErrorKind::NotConnected, "dispatcher is not running");
+        let (reply, flows) = mpsc::channel();
+        self.requests.send(reply).map_err(|_| not_running())?;
+        self.waker.wake()?;
+        flows.recv_timeout(deadline).map_err(|err| match err {
+            mpsc::RecvTimeoutError::Timeout => Error::new(ErrorKind::TimedOut, "dispatcher did not answer in time"),
+            mpsc::RecvTimeoutError::Disconnected => not_running(),
+        })
+    }
+}
+
+impl Dispatcher {
+    // Serve `interface`, on error its fd is left open, still the caller's
+    pub fn new(interface: File, logging: Logging, config: &Config) -> Result<Self> {
+        let (poll, waker) = match register(&interface) {
+            Ok(registered) => registered,
+            Err(err) => {
+                let _ = interface.into_raw_fd();
+                return Err(err);
+            }
+        };
+        let waker = Arc::new(waker);
+        let stopper = Stopper {
+            stopping: Arc::new(AtomicBool::new(false)),
+            waker: Arc::clone(&waker),
+            done: Arc::new((Mutex::new(false), Condvar::new())),
+        };
+        let (requests, inspections) = mpsc::channel();
+
+        Ok(Self {
+            poll,
+            interface,
+            logging,
+            router: Router::default(),
+            next_id: FIRST_FLOW,
+            id_step: 1,
+            max_flows: config.max_flows.max(1),
+            policy: config.overflow_policy,
+            timeouts: config.timeouts,
+            proxy: config.proxy.clone(),
+            dns: config.dns,
+            last_sweep: Instant::now(),
+            interface_pending: false,
+            stopper,
+            stats: Arc::default(),
+            inspector: Inspector { requests, waker },
+            inspections,
+            names: Cache::new(DNS_CACHE_CAPACITY),
+            capture: None,
+            framing: config.framing,
+            verify_checksums: config.verify_checksums,
+            gateway: config.gateway,
+            icmp_sockets: true,
+            batch: Batch::default(),
+            pool: Pool::new(config.framing.header_len() + max_packet(config.framing), 2 * BATCH),
+            outgoing: Vec::with_capacity(BATCH),
+            buf: vec![0; config.framing.header_len() + max_packet(config.framing)],
+        })
+    }
+
+    // Give the interface back without closing it, the dispatcher never ran
+    pub fn into_interface(self) -> File {
+        self.interface
+    }
+
+    pub fn stopper(&self) -> Stopper {
+        self.stopper.clone()
+    }
+
+    pub fn stats(&self) -> Arc<Stats> {
+        Arc::clone(&self.stats)
+    }
+
+    pub fn inspector(&self) -> Inspector {
+        self.inspector.clone()
+    }
+
+    // Count into `stats`, shared with the dispatchers of the other queues
+    pub fn set_stats(&mut self, stats: Arc<Stats>) {
+        self.stats = stats;
+    }
+
+    // Serve queue `queue` of a multiqueue device: flow ids stay unique across the `queues` dispatchers
+    // and each gets its share of the flow table. Call before running
+    pub fn set_queue(&mut self, queue: usize, queues: usize) {
+        let queues = queues.max(1);
+        self.next_id = FIRST_FLOW + queue;
+        self.id_step = queues;
+        self.max_flows = self.max_flows.div_ceil(queues);
+    }
+
+    // Record the packets read from and written to the interface from now on
+    pub fn set_capture(&mut self, capture: Arc<Mutex<Capture>>) {
+        self.capture = Some(capture);
+    }
+
+    // Snapshot of every open flow
+    pub fn flows(&self) -> Vec<FlowInfo> {
+        let now = Instant::now();
+        let mut flows = self.router.infos(now);
+        flows.sort_by_key(|flow| flow.id);
+        flows
+    }
+
+    // Run until stopped, or until the interface reaches its end or fails
+    pub fn run(&mut self) -> Result<()> {
+        // A panic must still release whoever waits in `Stopper::wait`
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.poll_loop()))
+            .unwrap_or_else(|_| Err(Error::other("dispatcher panicked")));
+        self.stopper.finish();
+        result
+    }
+
+    fn poll_loop(&mut self) -> Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        loop {
+            let timeout = if self.interface_pending { Duration::ZERO } else { TICK };
+            if let Err(err) = self.poll.poll(&mut events, Some(timeout)) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                self.close_all(None);
+                return Err(err);
+            }
+
+            // Replies from upstream are handled before more of the interface is read
+            let mut interface = self.interface_pending;
+            for event in events.iter() {
+                match event.token() {
+                    INTERFACE => interface = true,
+                    WAKER => {
+                        if self.stopper.stopping.load(Ordering::SeqCst) {
+                            log!(self.logging, Info, Tun, "stopping, close {} flows", self.router.len());
+                            self.close_all(Some(*RST_ACK));
+                            return Ok(());
+                        }
+                        while let Ok(reply) = self.inspections.try_recv() {
+                            reply.send(self.flows()).unwrap_or(());
+                        }
+                    }
+                    Token(id) => {
+                        self.handle_upstream(id, event.is_readable(), event.is_writable());
+                    }
+                }
+            }
+            if interface && !self.read_interface() {
+                self.close_all(None);
+                return Ok(());
+            }
+
+            if self.last_sweep.elapsed() >= TICK {
+                self.sweep();
+                self.last_sweep = Instant::now();
+            }
+            self.flush();
+        }
+    }
+
+    // Drain the interface a batch at a time up to its budget, returns false once it is closed
+    fn read_interface(&mut self) -> bool {
+        let mut bufs: Vec<Vec<u8>> = (0..BATCH).map(|_| self.pool.take()).collect();
+        let mut lens = [0; BATCH];
+        let mut budget = INTERFACE_BUDGET;
+        self.interface_pending = false;
+        let open = loop {
+            if budget == 0 {
+                self.interface_pending = true;
+                break true;
+            }
+            budget -= 1;
+            let n = match self.batch.read(self.interface.as_raw_fd(), &mut bufs, &mut lens) {
+                Ok(0) => {
+                    log!(self.logging, Info, Tun, "interface closed");
+                    break false;
+                }
+                Ok(n) => n,
+                Err(err) => match err.kind() {
+                    ErrorKind::WouldBlock => break true,
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        log!(self.logging, Error, Tun, "read error: {err}");
+                        break false;
+                    }
+                },
+            };
+            for (buf, &len) in bufs.iter().zip(&lens[..n]) {
+                self.handle_frame(&buf[..len]);
+            }
+            // The responses to this batch go out together
+            self.flush();
+        };
+        for buf in bufs {
+            self.pool.give(buf);
+        }
+        open
+    }
+
+    // One frame read from the interface
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let n = frame.len();
+        self.stats.packet_up();
+        // The transport checksum is left to the device, only partly computed
+        let mut partial = false;
+        if self.framing == PacketFraming::VirtioNet {
+            // TCP super-packets are handled whole
+            let header = vnet::Header::parse(frame).unwrap_or_default();
+            partial = header.flags & vnet::F_NEEDS_CSUM != 0;
+            if header.gso_type != vnet::GSO_NONE {
+                log!(self.logging, Trace, Tun, "super-packet, len({n}), {header:?}");
+            }
+        }
+        let bytes = match self.framing.unframe(frame) {
+            Some(bytes) => bytes,
+            None => {
+                log!(self.logging, Debug, Tun, "not an IP packet for {:?}, len({n}){}", self.framing, Hex(frame));
+                self.stats.dropped(DropReason::Malformed);
+                return;
+            }
+        };
+
+        if let Some(capture) = &self.capture {
+            lock(capture).packet(Direction::Up, bytes);
+        }
+        if bytes.len() < 20 {
+            log!(self.logging, Debug, Tun, "short packet, len({n}){}", Hex(bytes));
+            self.stats.dropped(DropReason::Malformed);
+            return;
+        }
+        log!(self.logging, Trace, Tun, "--->> recv len({n}){}", Hex(bytes));
+
+        let version = (bytes[0] >> 4) & 0b1111;
+        if version != 4 {
+            log!(self.logging, Debug, Tun, "unsupported version {version}");
+            self.stats.dropped(DropReason::UnsupportedVersion);
+            return;
+        };
+        let packet = match Ipv4::new(bytes) {
+            Some(packet) => packet,
+            None => {
+                log!(self.logging, Debug, Tun, "bad header length, len({n}){}", Hex(bytes));
+                self.stats.dropped(DropReason::Malformed);
+                return;
+            }
+        };
+        if self.verify_checksums && !packet.checksums_valid(!partial) {
+            log!(self.logging, Debug, Tun, "bad checksum, len({n}){}", Hex(bytes));
+            self.stats.dropped(DropReason::BadChecksum);
+            return;
+        }
+
+        // UDP of an open flow goes straight from the read buffer to the upstream socket, unless its time
+        // to live runs out or it is a fragment. TCP still takes the copy into a `Datagram`, the replies of
+        // a flow are packed from its latest segment
+        if let (Some(udp), Some(key), true) = (packet.udp(), packet.key(), packet.ttl() > 1 && !packet.is_fragment()) {
+            if let Some(flow) = self.router.get(&key).filter(|flow| matches!(flow.state, State::UDP(_))) {
+                flow.packets_up += 1;
+                let id = flow.id;
+                self.router.touch(id);
+                self.handle_udp(id, packet.ttl(), key.dst, udp.payload());
+                return;
+            }
+        }
+        match Datagram::new(bytes) {
+            Ok(datagram) => self.forward(datagram),
+            Err(err) => {
+                log!(self.logging, Debug, Tun, "malformed packet: {err}, len({n}){}", Hex(bytes));
+                self.stats.dropped(DropReason::Malformed);
+            }
+        }
+    }
+
+    // Hand the datagram to its flow, opening a new flow when there is none, and write the responses
+    pub fn execute(&mut self, datagram: Datagram) {
+        self.forward(datagram);
+        self.flush();
+    }
+
+    // `execute` leaving the responses queued
+    fn forward(&mut self, datagram: Datagram) {
+        let key = datagram.key();
+        if datagram.header.ttl <= 1 {
+            self.expire(key, &datagram);
+            return;
+        }
+        if let Some(flow) = self.router.get(&key).map(|flow| flow.id).and_then(|id| self.router.touch(id)) {
+            flow.packets_up += 1;
+            let id = flow.id;
+            match flow.state {
+                State::TCP(_) => {
+                    flow.datagram = datagram;
+                    self.handle_tcp(id);
+                }
+                State::UDP(_) => self.handle_udp(id, datagram.header.ttl, datagram.payload.dst_addr(), datagram.payload.payload()),
+                State::ICMP(_) => {
+                    flow.datagram = datagram;
+                    self.handle_icmp(id);
+                }
+            }
+            return;
+        }
+
+        // Of ICMP, only echo requests go anywhere
+        let protocol = datagram.protocol();
+        let echo = datagram.payload.echo().is_some();
+        if matches!(protocol, Protocol::UNKNOWN) || matches!(protocol, Protocol::ICMP) && !echo {
+            log!(self.logging, Debug, Tun, tuple = key; "unsupported protocol {:?}, drop", protocol);
+            self.stats.dropped(DropReason::UnsupportedProtocol);
+            return;
+        }
+
+        // Only a SYN opens a TCP flow, anything else belongs to a closed one
+        if let Protocol::TCP = protocol {
+            let payload = &datagram.payload;
+            let flags = *payload.flags_type();
+            if flags & *SYN == 0 {
+                self.stats.dropped(DropReason::NoFlow);
+                if !payload.payload().is_empty() || flags & *FIN != 0 {
+                    log!(self.logging, Debug, Tcp, tuple = key; "no flow, reset");
+                    if let Some(pkt) = reset(&datagram) {
+                        self.respond(&pkt);
+                    }
+                }
+                return;
+            }
+        }
+
+        if self.router.len() >= self.max_flows {
+            match self.policy {
+                OverflowPolicy::Reset => {
+                    log!(self.logging, Warn, Pool, tuple = key; "flow table full({}), reset", self.max_flows);
+                    self.stats.dropped(DropReason::FlowTableFull);
+                    let pkt = reset(&datagram).unwrap_or_else(|| unreachable(&datagram, icmp::ADMINISTRATIVELY_PROHIBITED));
+                    self.respond(&pkt);
+                    return;
+                }
+                OverflowPolicy::Unreachable => {
+                    log!(self.logging, Warn, Pool, tuple = key; "flow table full({}), unreachable", self.max_flows);
+                    self.stats.dropped(DropReason::FlowTableFull);
+                    self.respond(&unreachable(&datagram, icmp::ADMINISTRATIVELY_PROHIBITED));
+                    return;
+                }
+                OverflowPolicy::EvictLru => {
+                    if let Some(id) = self.router.lru() {
+                        flow_log!(self, Info, Pool, id, "flow table full({}), evict", self.max_flows);
+                        self.stats.eviction();
+                        self.close(id, Some(*RST));
+                    }
+                }
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += self.id_step;
+        match protocol {
+            Protocol::TCP => self.open_tcp(id, key, datagram),
+            Protocol::UDP => self.open_udp(id, key, datagram),
+            Protocol::ICMP => self.open_icmp(id, key, datagram),
+            _ => {}
+        }
+    }
+
+    // The interface is the first hop, the datagram has no time to live left for the next one. Answered
+    // with time exceeded from the gateway, unless it is ICMP other than an echo request
+    fn expire(&mut self, key: FlowKey, datagram: &Datagram) {
+        self.stats.dropped(DropReason::TtlExceeded);
+        if matches!(datagram.protocol(), Protocol::ICMP) && datagram.payload.echo().is_none() {
+            return;
+        }
+        log!(self.logging, Debug, Tun, tuple = key; "ttl exceeded");
+        let from = self.gateway.map_or(datagram.header.dst_ip, |gateway| gateway.octets());
+        let icmp = icmp::time_exceeded(icmp::TTL_EXCEEDED, &datagram.origin);
+        self.respond(&datagram.icmp_resp_pack_from(from, &icmp));
+    }
+
+    fn handle_upstream(&mut self, id: usize, readable: bool, writable: bool) {
+        let state = match self.router.flow(id) {
+            Some(flow) => flow.state,
+            None => return, // Closed while events were pending
+        };
+
+        match state {
+            State::TCP(_) => self.tcp_ready(id, readable, writable),
+            State::UDP(_) => self.udp_ready(id),
+            State::ICMP(_) => self.icmp_ready(id),
+        }
+    }
+
+    fn register(&mut self, id: usize, upstream: &mut Upstream) -> Result<()> {
+        let registry = self.poll.registry();
+        match upstream {
+            Upstream::Tcp(stream) => registry.register(stream, Token(id), Interest::READABLE | Interest::WRITABLE),
+            Upstream::Udp(socket) => registry.register(socket, Token(id), Interest::READABLE),
+            Upstream::Icmp(socket) => registry.register(socket, Token(id), Interest::READABLE),
+            Upstream::Socks5(client) => registry.register(client.stream(), Token(id), Interest::READABLE | Interest::WRITABLE),
+        }
+    }
+
+    // Remove the flow, telling a TCP client with `flags` when given
+    fn close(&mut self, id: usize, flags: Option<u8>) {
+        let mut flow = match self.router.delete(id) {
+            Some(flow) => flow,
+            None => return,
+        };
+        self.stats.flow_closed(flow.state.protocol());
+
+        if let (Some(flags), State::TCP(_)) = (flags, flow.state) {
+            let pkt = flow.segment(flags, &[]);
+            self.respond(&pkt);
+        }
+
+        let registry = self.poll.registry();
+        let result = match &mut flow.upstream {
+            Upstream::Tcp(stream) => registry.deregister(stream),
+            Upstream::Udp(socket) => registry.deregister(socket),
+            Upstream::Icmp(socket) => registry.deregister(socket),
+            Upstream::Socks5(client) => registry.deregister(client.stream()),
+        };
+        if let Err(err) = result {
+            log!(self.logging, Debug, Pool, flow = id, tuple = flow.name; "deregister error: {err}");
+        }
+        log!(self.logging, Debug, Pool, flow = id, tuple = flow.name; "closed");
+    }
+
+    // Close a flow whose upstream failed with `err`, telling the client why
+    pub(crate) fn fail(&mut self, id: usize, err: &Error) {
+        let refusal = self.router.flow(id).and_then(|flow| refusal(&flow.datagram, err));
+        self.close(id, None);
+        if let Some(pkt) = refusal {
+            self.respond(&pkt);
+        }
+    }
+
+    fn close_all(&mut self, flags: Option<u8>) {
+        for id in self.router.ids() {
+            self.close(id, flags);
+        }
+        self.flush();
+    }
+
+    // Close flows idle for longer than their timeout
+    fn sweep(&mut self) {
+        let now = Instant::now();
+        let timeouts = self.timeouts;
+        for id in self.router.ids() {
+            let flow = match self.router.flow(id) {
+                Some(flow) => flow,
+                None => continue,
+            };
+
+            let idle = now.duration_since(flow.last_active);
+            let (expired, flags) = match flow.state {
+                State::TCP(TcpState::Connecting) => {
+                    (now.duration_since(flow.created) > timeouts.tcp_connect, Some(*RST_ACK))
+                }
+                State::TCP(TcpState::FinWait) | State::TCP(TcpState::Closing) => {
+                    (idle > timeouts.tcp_half_closed, Some(*RST))
+                }
+                State::TCP(_) => (idle > timeouts.tcp_established, Some(*RST)),
+                State::UDP(_) if flow.datagram.payload.dst_addr().port() == DNS_PORT => (idle > timeouts.dns, None),
+                State::UDP(_) => (idle > timeouts.udp, None),
+                State::ICMP(_) => (idle > timeouts.icmp, None),
+            };
+
+            if expired {
+                if flow.state == State::TCP(TcpState::Connecting) {
+                    self.stats.connect_failure();
+                } else {
+                    self.stats.idle_timeout();
+                }
+                flow_log!(self, Debug, Pool, id, "idle timeout({:?})", idle);
+                self.close(id, flags);
+            }
+        }
+    }
+
+    // Data read from an upstream TCP connection at once, it goes down as one packet
+    pub(crate) fn segment_size(&self) -> usize {
+        match self.framing {
+            PacketFraming::VirtioNet => MAX_SEGMENT,
+            _ => MSS,
+        }
+    }
+
+    // Reply to the client through the interface, queued until the next `flush`
+    fn respond(&mut self, pkt: &[u8]) {
+        log!(self.logging, Trace, Tun, "<<--- respond len({}){}", pkt.len(), Hex(pkt));
+        let frame = if self.framing.header_len() + pkt.len() <= self.pool.size() {
+            let mut buf = self.pool.take();
+            let len = self.framing.frame_into(pkt, MTU, &mut buf);
+            Frame { buf, len }
+        } else {
+            // Larger than any packet read, the pool does not take it back
+            let buf = self.framing.frame(pkt, MTU);
+            Frame { len: buf.len(), buf }
+        };
+        self.outgoing.push(frame);
+        if self.outgoing.len() >= BATCH {
+            self.flush();
+        }
+    }
+
+    // Write the queued responses, a batch per system call
+    fn flush(&mut self) {
+        let mut done = 0;
+        while done < self.outgoing.len() {
+            match self.batch.write(self.interface.as_raw_fd(), &self.outgoing[done..]) {
+                Ok(n) => {
+                    for frame in &self.outgoing[done..done + n] {
+                        self.stats.packet_down();
+                        if let Some(capture) = &self.capture {
+                            lock(capture).packet(Direction::Down, &frame.as_ref()[self.framing.header_len()..]);
+                        }
+                    }
+                    done += n;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    log!(self.logging, Warn, Tun, "write error: {err}");
+                    self.stats.dropped(DropReason::WriteError);
+                    done += 1;
+                }
+            }
+        }
+        for frame in self.outgoing.drain(..) {
+            self.pool.give(frame.buf);
+        }
+    }
+
+    // Reply to the client of the flow with a packet built from its latest datagram
+    fn reply(&mut self, id: usize, reply: Reply) {
+        let pkt = match self.router.flow(id) {
+            Some(flow) => {
+                flow.packets_down += 1;
+                match reply {
+                    Reply::Segment(flags, data) => flow.segment(flags, data),
+                    Reply::Datagram(src, data) => flow.datagram.udp_resp_pack_from(src, data),
+                }
+            }
+            None => return,
+        };
+        self.respond(&pkt);
+    }
+}
+
+// What goes back to the client of a flow
+pub(crate) enum Reply<'a> {
+    // TCP segment with its flags
+    Segment(u8, &'a [u8]),
+    // UDP datagram from the remote that sent it
+    Datagram(SocketAddrV4, &'a [u8]),
+}
+
+// Largest packet read from or written to the interface
+fn max_packet(framing: PacketFraming) -> usize {
+    match framing {
+        PacketFraming::VirtioNet => vnet::MAX_PACKET,
+        _ => MTU,
+    }
+}
+
+fn lock(capture: &Mutex<Capture>) -> std::sync::MutexGuard<'_, Capture> {
+    capture.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Poll of the interface, with the waker of the other threads
+fn register(interface: &File) -> Result<(Poll, Waker)> {
+    set_nonblocking(interface.as_raw_fd())?;
+    let poll = Poll::new()?;
+    poll.registry().register(&mut SourceFd(&interface.as_raw_fd()), INTERFACE, Interest::READABLE)?;
+    let waker = Waker::new(poll.registry(), WAKER)?;
+    Ok((poll, waker))
+}
+
+fn set_nonblocking(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+// RST answering the datagram, never answer a RST
+fn reset(datagram: &Datagram) -> Option<Vec<u8>> {
+    let payload = &datagram.payload;
+    if !matches!(payload.protocol(), Protocol::TCP) || *payload.flags_type() & *RST != 0 {
+        return None;
+    }
+    Some(datagram.resp_pack(&payload.pack(&[*RST_ACK], &[])))
+}
+
+// ICMP destination unreachable quoting the datagram
+fn unreachable(datagram: &Datagram, code: u8) -> Vec<u8> {
+    datagram.icmp_resp_pack(&icmp::destination_unreachable(code, &datagram.origin))
+}
+
+// What tells the client that the upstream of its datagram failed with `err`, so it fails fast instead
+// of timing out: no route or a policy in the way is ICMP unreachable, a refused TCP connection a RST
+// and a refused UDP one port unreachable. Local failures are a RST for TCP, nothing otherwise
+fn refusal(datagram: &Datagram, err: &Error) -> Option<Vec<u8>> {
+    let code = match err.kind() {
+        ErrorKind::NetworkUnreachable | ErrorKind::NetworkDown => icmp::NET_UNREACHABLE,
+        ErrorKind::HostUnreachable => icmp::HOST_UNREACHABLE,
+        ErrorKind::PermissionDenied => icmp::ADMINISTRATIVELY_PROHIBITED,
+        ErrorKind::ConnectionRefused if matches!(datagram.protocol(), Protocol::UDP) => icmp::PORT_UNREACHABLE,
+        _ => return reset(datagram),
+    };
+    Some(unreachable(datagram, code))
+}
 
 pub fn handle_datagram(datagram: &[u8], stream: &mut File, logging: &mut Logging) {
-    logging.i(format!("--->> Recv: len({}), {:?}", (&datagram).len(), &datagram));
+    log!(logging, Trace, Tun, "--->> recv len({}){}", datagram.len(), Hex(datagram));
+    if datagram.is_empty() {
+        log!(logging, Error, Tun, "empty datagram");
+        return;
+    }
 
     // msg[0] & 4 == 4 #ipv4
     // msg[0] & 6 == 6 #ipv6
@@ -23,27 +788,27 @@ pub fn handle_datagram(datagram: &[u8], stream: &mut File, logging: &mut Logging
     match version {
         4 => {
             // stream.read(&mut buf2) // Read remaining bytes error: OS(11), Operation would block.
-            let data = datagram.to_vec();
-            let mut copy_stream = stream.try_clone().unwrap();
-            let mut copy_logging = logging.clone();
-
-            let s = thread::spawn(move || {
-                dispatch(data, &mut copy_stream, &mut copy_logging);
-            });
-
-            s.join().expect("TODO: panic message");
+            if panic::catch_unwind(AssertUnwindSafe(|| dispatch(datagram, stream, logging))).is_err() {
+                log!(logging, Error, Tun, "dispatch panicked");
+            }
         }
         6 => {
-            logging.w("Unsupported version ipv6".to_string());
+            log!(logging, Debug, Tun, "unsupported version ipv6");
         }
         _ => {
-            logging.e(format!("Error version {}", version));
+            log!(logging, Debug, Tun, "unsupported version {version}");
         }
     }
 }
 
-pub fn dispatch(data: Vec<u8>, stream: &mut File, logging: &mut Logging) {
-    let datagram = Datagram::new(&data);
+pub fn dispatch(data: &[u8], stream: &mut File, logging: &mut Logging) {
+    let datagram = match Datagram::new(data) {
+        Ok(datagram) => datagram,
+        Err(err) => {
+            log!(logging, Debug, Tun, "malformed packet: {err}");
+            return;
+        }
+    };
     let ip_header = &datagram.header;
 
     // Fragment
@@ -51,12 +816,12 @@ pub fn dispatch(data: Vec<u8>, stream: &mut File, logging: &mut Logging) {
     let mf = (ip_header.flags_fragment_offset[0] >> 5) & 1;
     let offset = bytes_to_u32_no_prefix(&ip_header.flags_fragment_offset, 3);
 
-    logging.i(format!("--->> {id}: {:?}: {:?} => {:?}, IHL({}), ID({id}), MF({mf}), OFFSET({offset})",
-                      &datagram.protocol(),
-                      IpAddr::from(ip_header.src_ip),
-                      IpAddr::from(ip_header.dst_ip),
-                      ip_header.version_ihl & 0x0F,
-    ));
+    log!(logging, Trace, Tun, "--->> {id}: {:?}: {:?} => {:?}, IHL({}), ID({id}), MF({mf}), OFFSET({offset})",
+         &datagram.protocol(),
+         IpAddr::from(ip_header.src_ip),
+         IpAddr::from(ip_header.dst_ip),
+         ip_header.version_ihl & 0x0F,
+    );
 
     let pseudo_header = PseudoHeader {
         src_ip: ip_header.dst_ip,
@@ -68,52 +833,60 @@ pub fn dispatch(data: Vec<u8>, stream: &mut File, logging: &mut Logging) {
     let protocol = &datagram.protocol();
 
     // let packet = build_packet(protocol, &datagram.payload, pseudo_header);
-    let packet = build_packet(protocol, &[], pseudo_header);
-    logging.i(packet.info());
+    let packet = match build_packet(protocol, &[], pseudo_header) {
+        Ok(packet) => packet,
+        Err(err) => {
+            log!(logging, Debug, Tun, "malformed {:?}: {err}", protocol);
+            return;
+        }
+    };
+    log!(logging, Trace, Tun, "{}", packet.info());
 
     if let Protocol::UNKNOWN = protocol {
-        logging.e("Unsupported unknown protocol\n".to_string());
+        log!(logging, Debug, Tun, "unsupported unknown protocol");
         return;
     }
 
     let response = Simulator::handle(protocol, &packet);
 
-    if response.len() == 0 {
+    if response.is_empty() {
         return;
     }
 
     for msg in response {
-        logging.i(format!("<<--- Respond {}", build_packet(protocol, &msg, pseudo_header).info()));
-        let ip_packet = datagram.resp_pack(&msg);
-        logging.i(format!("<<--- Send: {:?}({id}), len({}), packet: {:?}\n", protocol, &ip_packet.len(), &ip_packet));
+        if let Ok(packet) = build_packet(protocol, &msg, pseudo_header) {
+            log!(logging, Trace, Tun, "<<--- respond {}", packet.info());
+        }
+        let ip_packet = PacketFraming::default().frame(&datagram.resp_pack(&msg), MTU);
+        log!(logging, Trace, Tun, "<<--- send {:?}({id}), len({}){}", protocol, ip_packet.len(), Hex(&ip_packet));
 
         match stream.write(&ip_packet) {
             Ok(n) => {
                 if n != ip_packet.len() {
-                    logging.i(format!("<<--- Send: {:?}({id}) error, write size({n}\n, len({}))", protocol, ip_packet.len()));
+                    log!(logging, Warn, Tun, "<<--- send {:?}({id}) error, write size({n}), len({})", protocol, ip_packet.len());
                 }
             }
             Err(err) => {
-                logging.e(format!("<<--- Send: {:?}({id}) error: {}\n", protocol, err))
+                log!(logging, Warn, Tun, "<<--- send {:?}({id}) error: {err}", protocol)
             }
         }
     }
 }
 
-fn build_packet(protocol: &Protocol, data: &[u8], pseudo_header: PseudoHeader) -> Box<dyn Packet + Send + Sync> {
-    return match protocol {
+fn build_packet(protocol: &Protocol, data: &[u8], pseudo_header: PseudoHeader) -> std::result::Result<Box<dyn Packet + Send + Sync>, ParseError> {
+    Ok(match protocol {
         Protocol::TCP => {
-            Box::new(Tcp::new(data, pseudo_header))
+            Box::new(Tcp::new(data, pseudo_header)?)
         }
         Protocol::UDP => {
-            Box::new(Udp::new(data, pseudo_header))
+            Box::new(Udp::new(data, pseudo_header)?)
         }
         Protocol::ICMP => {
-            Box::new(Icmp::new(data))
+            Box::new(Icmp::new(data)?)
         }
         // Protocol::UNKNOWN => {}
         _ => {
-            Box::new(Udp::new(data, pseudo_header))
+            Box::new(Udp::new(data, pseudo_header)?)
         }
-    };
+    })
 }
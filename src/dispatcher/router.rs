@@ -0,0 +1,82 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Instant;
+
+use crate::dispatcher::DNS_PORT;
+use crate::dispatcher::flow::{Flow, FlowInfo};
+use crate::protocol::internet::UDP;
+use crate::protocol::internet::view::FlowKey;
+
+// Flow table: routes a 5-tuple to its flow, flows are keyed by id (their poll token). UDP is routed by
+// the client's address alone, one session and upstream socket per local port talks to any remote
+// (endpoint-independent mapping, RFC 4787). DNS queries keep their 5-tuple, they go to the resolver
+// they were sent to, or the one of `DnsMode::Server`
+#[derive(Default)]
+pub struct Router {
+    routes: HashMap<FlowKey, usize>,
+    flows: HashMap<usize, Flow>,
+    // Flows by their last activity, the least recently active first
+    active: BTreeSet<(Instant, usize)>,
+}
+
+impl Router {
+    pub fn insert(&mut self, flow: Flow) {
+        self.routes.insert(route(&flow.key), flow.id);
+        self.active.insert((flow.last_active, flow.id));
+        self.flows.insert(flow.id, flow);
+    }
+
+    pub fn get(&mut self, key: &FlowKey) -> Option<&mut Flow> {
+        let id = self.routes.get(&route(key))?;
+        self.flows.get_mut(id)
+    }
+
+    // Name of flow `id`, empty once it is closed
+    pub fn name(&self, id: usize) -> &str {
+        self.flows.get(&id).map(|flow| flow.name.as_str()).unwrap_or_default()
+    }
+
+    pub fn flow(&mut self, id: usize) -> Option<&mut Flow> {
+        self.flows.get_mut(&id)
+    }
+
+    // Flow `id` was active now, every update of `last_active` goes through here to keep `lru` in order
+    pub fn touch(&mut self, id: usize) -> Option<&mut Flow> {
+        let flow = self.flows.get_mut(&id)?;
+        self.active.remove(&(flow.last_active, id));
+        flow.last_active = Instant::now();
+        self.active.insert((flow.last_active, id));
+        Some(flow)
+    }
+
+    pub fn delete(&mut self, id: usize) -> Option<Flow> {
+        let flow = self.flows.remove(&id)?;
+        self.routes.remove(&route(&flow.key));
+        self.active.remove(&(flow.last_active, id));
+        Some(flow)
+    }
+
+    // The least recently active flow
+    pub fn lru(&self) -> Option<usize> {
+        self.active.first().map(|&(_, id)| id)
+    }
+
+    pub fn infos(&self, now: Instant) -> Vec<FlowInfo> {
+        self.flows.values().map(|flow| flow.info(now)).collect()
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.flows.keys().copied().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+}
+
+fn route(key: &FlowKey) -> FlowKey {
+    match key.protocol {
+        UDP if key.dst.port() != DNS_PORT => FlowKey { dst: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)), ..*key },
+        _ => *key,
+    }
+}
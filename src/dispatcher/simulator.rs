@@ -6,25 +6,25 @@ pub struct Simulator;
 type Pkt = Box<dyn Packet + Send + Sync>;
 impl Simulator {
     pub fn handle(protocol: &Protocol, packet: &Pkt) -> Vec<Vec<u8>> {
-        return match protocol {
+        match protocol {
             Protocol::TCP => { Self::handle_tcp(packet) }
             Protocol::UDP => { Self::handle_udp(packet) }
             Protocol::ICMP => { Self::handle_icmp(packet) }
             Protocol::UNKNOWN => { vec![vec![]] }
-        };
+        }
     }
 
     pub fn handle_tcp(tcp: &Pkt) -> Vec<Vec<u8>> {
-        return match tcp.flags_type() {
+        match tcp.flags_type() {
             SYN | SEW => {
-                let response = tcp.pack(&[*SYN_ACK], &vec![]);
+                let response = tcp.pack(&[*SYN_ACK], &[]);
                 vec![response]
             }
             PSH_ACK => {
                 let msg = tcp.payload();
                 let mut data = "Hello ".as_bytes().to_vec();
                 data.extend_from_slice(msg);
-                let ack_response = tcp.pack(&[*ACK], &vec![]);
+                let ack_response = tcp.pack(&[*ACK], &[]);
                 let msg_response = tcp.pack(&[*PSH_ACK], &data);
                 vec![ack_response, msg_response]
             }
@@ -32,8 +32,8 @@ impl Simulator {
                 vec![]
             }
             FIN_ACK => {
-                let ack_response = tcp.pack(&[*ACK], &vec![]);
-                let fin_ack_response = tcp.pack(&[*FIN_ACK], &vec![]);
+                let ack_response = tcp.pack(&[*ACK], &[]);
+                let fin_ack_response = tcp.pack(&[*FIN_ACK], &[]);
                 vec![ack_response, fin_ack_response]
             }
             RST => {
@@ -45,19 +45,19 @@ impl Simulator {
             _ => {
                 vec![]
             }
-        };
+        }
     }
 
     pub fn handle_udp(udp: &Pkt) -> Vec<Vec<u8>> {
         let mut msg = Vec::new();
         msg.extend_from_slice("Hello ".as_bytes());
-        msg.extend_from_slice(&udp.payload());
+        msg.extend_from_slice(udp.payload());
         // let response = udp.pack(&[], &msg);
         vec![msg]
     }
 
     fn handle_icmp(icmp: &Pkt) -> Vec<Vec<u8>> {
-        let response = icmp.pack(&[], &vec![]);
+        let response = icmp.pack(&[], &[]);
         vec![response]
     }
 }
\ No newline at end of file
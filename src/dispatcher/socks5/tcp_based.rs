@@ -1,175 +1,195 @@
 use std::io::{Error, ErrorKind, Read, Result, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
-use std::sync::Arc;
-use std::thread;
-use std::thread::JoinHandle;
-use std::time::Duration;
+use std::net::{IpAddr, Shutdown, SocketAddr};
 
-use crate::protocol::internet::tcp::PSH_ACK;
+use mio::net::TcpStream;
+
+use crate::config::Auth;
 use crate::protocol::socks5::*;
-use crate::thread_pool::event::Event::MESSAGE;
-use crate::thread_pool::Reporter;
-use crate::util::bytes_to_u32;
 
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+
+// Non-blocking SOCKS5 CONNECT client, driven by readiness events of its stream
 pub struct Client {
-    server_addr: SocketAddr,
-    reporter: Reporter,
+    dst_atyp: u8,
     dst_addr: Vec<u8>,
     dst_port: [u8; 2],
     methods: Vec<u8>,
     method: u8,
-    stream: Option<TcpStream>,
-    job: Option<JoinHandle<()>>,
+    auth: Option<Auth>,
+    stream: TcpStream,
+    state: State,
+    buf: Vec<u8>,
 }
 
-impl Client {
-    pub fn new(server_addr: SocketAddr, dst_addr: &[u8], dst_port: [u8; 2], reporter: Reporter) -> Self {
-        Client {
-            server_addr,
-            reporter,
-            dst_addr: dst_addr.to_vec(),
-            dst_port,
-            methods: vec![],
-            method: 0,
-            stream: None,
-            job: None,
-        }
-    }
-
-    pub fn start(&mut self) -> Result<()> {
-        self.negotiate()?;
-        self.connect()?;
-        self.recv()
-    }
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    Connecting,
+    Negotiating,
+    Authenticating,
+    Requesting,
+    Established,
+}
 
-    fn negotiate(&mut self) -> Result<()> {
-        let mut stream = match TcpStream::connect_timeout(&self.server_addr, Duration::from_secs(5)) {
+impl Client {
+    // Start connecting to the server, the handshake goes on in `drive`.
+    // With `auth` the server may also pick username/password authentication
+    pub fn connect(server_addr: SocketAddr, dst: SocketAddr, auth: Option<Auth>) -> Result<Self> {
+        let stream = match TcpStream::connect(server_addr) {
             Ok(stream) => stream,
             Err(err) => {
-                let err = format!("[NEGOTIATE] Failed to connect to socks5 server: {:?}", err);
-                return Err(Error::new(ErrorKind::Other, err));
+                return Err(Error::new(err.kind(), format!("[NEGOTIATE] Failed to connect to socks5 server: {:?}", err)));
             }
         };
 
-        let request = negotiation::Request::new(&self.methods);
-        let bytes = request.as_bytes();
-
-        match stream.write_all(&bytes) {
-            Ok(_) => {}
-            Err(err) => {
-                let err = format!("[NEGOTIATE] Failed to write to socks5 server: {:?}", err);
-                return Err(Error::new(ErrorKind::Other, err));
-            }
-        }
-
-        let mut buf = [0; 1024];
-        let reply = match stream.read(&mut buf) {
-            Ok(n) => { negotiation::Reply::new(&buf[..n]) }
-            Err(err) => {
-                let err = format!("[NEGOTIATE] Failed to read from socks5 server: {:?}", err);
-                return Err(Error::new(ErrorKind::Other, err));
-            }
+        let (dst_atyp, dst_addr) = match dst.ip() {
+            IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (4, ip.octets().to_vec()),
         };
+        Ok(Client {
+            dst_atyp,
+            dst_addr,
+            dst_port: dst.port().to_be_bytes(),
+            methods: match auth {
+                Some(_) => vec![NO_AUTHENTICATION, USERNAME_PASSWORD],
+                None => vec![NO_AUTHENTICATION],
+            },
+            method: NO_AUTHENTICATION,
+            auth,
+            stream,
+            state: State::Connecting,
+            buf: Vec::new(),
+        })
+    }
 
-        self.method = reply.method;
-        self.stream = Some(stream);
-
-        return Ok(());
+    pub fn state(&self) -> State {
+        self.state
     }
 
-    fn connect(&mut self) -> Result<()> {
-        let mut stream = if let Some(stream) = &self.stream {
-            stream
-        } else {
-            return Err(Error::new(ErrorKind::Other, "[CONNECT] No stream"));
-        };
+    pub fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
 
-        let request = request::TcpMessage::build_request(1, 1, (&self.dst_addr).to_vec(), self.dst_port);
-        let bytes = request.as_bytes();
+    // Advance the handshake on readiness, Ok(true) once the relay is established
+    pub fn drive(&mut self) -> Result<bool> {
+        loop {
+            match self.state {
+                State::Connecting => {
+                    if let Some(err) = self.stream.take_error()? {
+                        return Err(Error::new(err.kind(), format!("[NEGOTIATE] Failed to connect to socks5 server: {:?}", err)));
+                    }
+                    match self.stream.peer_addr() {
+                        Ok(_) => {}
+                        Err(err) if err.kind() == ErrorKind::NotConnected => return Ok(false),
+                        Err(err) => return Err(err),
+                    }
 
-        match stream.write_all(&bytes) {
-            Ok(_) => {}
-            Err(err) => {
-                let err = format!("[CONNECT] Failed to write to socks5 server: {:?}", err);
-                return Err(Error::new(ErrorKind::Other, err));
-            }
-        }
+                    let request = negotiation::Request::new(&self.methods);
+                    self.write("[NEGOTIATE]", &request.as_bytes())?;
+                    self.state = State::Negotiating;
+                }
+                State::Negotiating => {
+                    if !self.fill("[NEGOTIATE]", 2)? {
+                        return Ok(false);
+                    }
+                    let reply = negotiation::Reply::new(&self.buf)?;
+                    self.buf.clear();
+                    if !self.methods.contains(&reply.method) {
+                        return Err(Error::other("[NEGOTIATE] No acceptable methods"));
+                    }
+                    self.method = reply.method;
 
-        let mut buf = [0; 1024];
-        let n = match stream.read(&mut buf) {
-            Ok(n) => { n }
-            Err(err) => {
-                let err = format!("[CONNECT] Failed to read from socks5 server: {:?}", err);
-                return Err(Error::new(ErrorKind::Other, err));
+                    match (self.method, &self.auth) {
+                        (USERNAME_PASSWORD, Some(auth)) => {
+                            let request = auth::Request::new(&auth.username, &auth.password);
+                            self.write("[AUTH]", &request.as_bytes())?;
+                            self.state = State::Authenticating;
+                        }
+                        _ => self.request()?,
+                    }
+                }
+                State::Authenticating => {
+                    if !self.fill("[AUTH]", 2)? {
+                        return Ok(false);
+                    }
+                    let reply = auth::Reply::new(&self.buf)?;
+                    self.buf.clear();
+                    if reply.status != 0x00 {
+                        return Err(Error::other(format!("[AUTH] Rejected, status({})", reply.status)));
+                    }
+                    self.request()?;
+                }
+                State::Requesting => {
+                    // VER, REP, RSV, ATYP, then the bound address: IPv4, IPv6 or a name with its length first
+                    if !self.fill("[CONNECT]", 5)? {
+                        return Ok(false);
+                    }
+                    let len = request::TcpMessage::reply_len(self.buf[3], self.buf[4])?;
+                    if !self.fill("[CONNECT]", len)? {
+                        return Ok(false);
+                    }
+                    let reply = request::TcpMessage::parse_reply(&self.buf)?;
+                    self.buf.clear();
+                    if reply.opt != 0x00 {
+                        return Err(Error::new(reply_kind(reply.opt), format!("[CONNECT] Failed, reply({})", reply.opt)));
+                    }
+                    self.state = State::Established;
+                }
+                State::Established => return Ok(true),
             }
-        };
-
-        let reply = request::TcpMessage::parse_reply(&buf[..n]);
-        println!("Reply: {:?}", reply);
-        if reply.opt == 0x00 {
-            println!("[CONNECT] Success,  Server info: {:?}:{}", reply.addr, bytes_to_u32(&reply.port));
-        } else {
-            let err = "[CONNECT] Failed";
-            return Err(Error::new(ErrorKind::Other, err));
         }
+    }
 
+    fn request(&mut self) -> Result<()> {
+        let request = request::TcpMessage::build_request(1, self.dst_atyp, self.dst_addr.to_vec(), self.dst_port);
+        self.write("[CONNECT]", &request.as_bytes())?;
+        self.state = State::Requesting;
         Ok(())
     }
 
-    pub fn send(&mut self, data: &[u8]) -> Result<()> {
-        let mut stream = if let Some(stream) = &self.stream {
-            stream
-        } else {
-            return Err(Error::new(ErrorKind::Other, "[Send] No stream"));
-        };
-
-        return match stream.write_all(data) {
-            Ok(_) => { Ok(()) }
+    // Handshake messages are tiny, a fresh connection accepts them whole
+    fn write(&mut self, stage: &str, bytes: &[u8]) -> Result<()> {
+        match self.stream.write_all(bytes) {
+            Ok(_) => Ok(()),
             Err(err) => {
-                let err = format!("[Send] Failed to send to socks5 server: {:?}", err);
-                Err(Error::new(ErrorKind::Other, err))
+                Err(Error::new(err.kind(), format!("{stage} Failed to write to socks5 server: {:?}", err)))
             }
-        };
+        }
     }
 
-    pub fn recv(&mut self) -> Result<()> {
-        let stream = if let Some(stream) = &self.stream {
-            stream
-        } else {
-            return Err(Error::new(ErrorKind::Other, "[Send] No stream"));
-        };
-        let mut stream_cloned = stream.try_clone().unwrap();
-        let reporter = Arc::clone(&self.reporter);
-        self.job = Some(thread::spawn(move || {
-            let mut buf = [0; 1024];
-            loop {
-                match stream_cloned.read(&mut buf) {
-                    Ok(n) => {
-                        if n == 0 {
-                            break;
-                        }
-                        MESSAGE(*PSH_ACK, buf[..n].to_vec()).report(0, &reporter);
-                        println!("[COMM] Recv {} bytes", n);
-                    }
-                    Err(err) => {
-                        println!("[COMM] Failed to recv from socks5 server: {:?}", err);
-                        break;
-                    }
+    // Read until `len` bytes are buffered, false when the server has not sent them yet
+    fn fill(&mut self, stage: &str, len: usize) -> Result<bool> {
+        let mut buf = [0; 64];
+        while self.buf.len() < len {
+            let want = (len - self.buf.len()).min(buf.len());
+            match self.stream.read(&mut buf[..want]) {
+                Ok(0) => {
+                    return Err(Error::other(format!("{stage} Socks5 server closed the connection")));
+                }
+                Ok(n) => self.buf.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    return Err(Error::new(err.kind(), format!("{stage} Failed to read from socks5 server: {:?}", err)));
                 }
             }
-        }));
-
-        return Ok(());
+        }
+        Ok(true)
     }
 
     pub fn stop(self) {
-        if let Some(stream) = self.stream {
-            stream.shutdown(Shutdown::Both).unwrap_or(());
-            drop(stream);
-        }
-        if let Some(job) = self.job {
-            drop(job);
-        }
-        drop(self.reporter);
+        self.stream.shutdown(Shutdown::Both).unwrap_or(());
     }
-}
\ No newline at end of file
+}
+
+// Kind of error of a failed CONNECT reply, what the client is told follows from it
+fn reply_kind(rep: u8) -> ErrorKind {
+    match rep {
+        0x02 => ErrorKind::PermissionDenied,
+        0x03 => ErrorKind::NetworkUnreachable,
+        0x04 => ErrorKind::HostUnreachable,
+        0x05 => ErrorKind::ConnectionRefused,
+        0x06 => ErrorKind::TimedOut,
+        _ => ErrorKind::Other,
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use crate::dns::message::Answer;
+
+// Short TTLs still name the connections that follow right after the lookup
+const MIN_TTL: Duration = Duration::from_secs(60);
+
+/// Domain names learned from the DNS responses passing through, by address.
+pub struct Cache {
+    entries: HashMap<Ipv4Addr, (String, Instant)>,
+    capacity: usize,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Self {
+        Self { entries: HashMap::new(), capacity }
+    }
+
+    pub fn insert(&mut self, answer: Answer) {
+        let now = Instant::now();
+        for (addr, ttl) in answer.addrs {
+            if self.entries.len() >= self.capacity && !self.entries.contains_key(&addr) {
+                self.entries.retain(|_, (_, expiry)| *expiry > now);
+                if self.entries.len() >= self.capacity {
+                    // Still full, drop the entry expiring first
+                    let first = self.entries.iter().min_by_key(|(_, (_, expiry))| *expiry).map(|(addr, _)| *addr);
+                    if let Some(first) = first {
+                        self.entries.remove(&first);
+                    }
+                }
+            }
+            self.entries.insert(addr, (answer.name.clone(), now + ttl.max(MIN_TTL)));
+        }
+    }
+
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<String> {
+        match self.entries.get(&addr) {
+            Some((name, expiry)) if *expiry > Instant::now() => Some(name.clone()),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -0,0 +1,87 @@
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+use crate::protocol::{need, ParseError};
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+// Upper bound of compression pointers followed for one name
+const MAX_JUMPS: usize = 16;
+
+/// Address records of a DNS response: the queried name and its IPv4 addresses with their TTL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answer {
+    pub name: String,
+    pub addrs: Vec<(Ipv4Addr, Duration)>,
+}
+
+/// Pick the A records out of a DNS response, an error when it is no response or malformed.
+pub fn parse_response(msg: &[u8]) -> Result<Answer, ParseError> {
+    let flags = u16_at(msg, 2)?;
+    let questions = u16_at(msg, 4)?;
+    let answers = u16_at(msg, 6)?;
+    if flags & 0x8000 == 0 {
+        return Err(ParseError::Unexpected("query"));
+    }
+    if questions != 1 {
+        return Err(ParseError::Unexpected("question count"));
+    }
+
+    let (name, mut offset) = read_name(msg, 12)?;
+    offset += 4; // QTYPE, QCLASS
+
+    let mut addrs = Vec::new();
+    for _ in 0..answers {
+        let (_, end) = read_name(msg, offset)?;
+        let rtype = u16_at(msg, end)?;
+        let class = u16_at(msg, end + 2)?;
+        let ttl = u32::from_be_bytes(bytes_at(msg, end + 4, 4)?.try_into().unwrap());
+        let len = u16_at(msg, end + 8)? as usize;
+        let data = bytes_at(msg, end + 10, len)?;
+        if rtype == TYPE_A && class == CLASS_IN && len == 4 {
+            addrs.push((Ipv4Addr::new(data[0], data[1], data[2], data[3]), Duration::from_secs(ttl as u64)));
+        }
+        offset = end + 10 + len;
+    }
+
+    Ok(Answer { name, addrs })
+}
+
+// Name starting at `offset`, and the offset right after it
+fn read_name(msg: &[u8], mut offset: usize) -> Result<(String, usize), ParseError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    for _ in 0..MAX_JUMPS {
+        loop {
+            let len = bytes_at(msg, offset, 1)?[0] as usize;
+            match len {
+                0 => {
+                    return Ok((labels.join("."), end.unwrap_or(offset + 1)));
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let pointer = (u16_at(msg, offset)? & 0x3FFF) as usize;
+                    end.get_or_insert(offset + 2);
+                    offset = pointer;
+                    break;
+                }
+                len => {
+                    let label = bytes_at(msg, offset + 1, len)?;
+                    labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                    offset += 1 + len;
+                }
+            }
+        }
+    }
+    Err(ParseError::Pointers)
+}
+
+// The `len` bytes at `offset`
+fn bytes_at(msg: &[u8], offset: usize, len: usize) -> Result<&[u8], ParseError> {
+    need(msg, offset + len)?;
+    Ok(&msg[offset..offset + len])
+}
+
+fn u16_at(msg: &[u8], offset: usize) -> Result<u16, ParseError> {
+    let bytes = bytes_at(msg, offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
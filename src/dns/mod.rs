@@ -1,5 +1,7 @@
 mod tdr_usage;
 mod resolver;
+pub mod message;
+pub mod cache;
 
 pub fn dns_resolve() {
     // trust_dns_resolver_usage::dns_resolve();
@@ -19,7 +19,7 @@ pub fn dns_resolve() {
     let received_data = &buf[..number_of_bytes];
 
     // 4. Parse response
-    let response = parse_dns_response(received_data);
+    let _response = parse_dns_response(received_data);
 }
 
 fn build_dns_query_message(domain_name: &str) -> Vec<u8> {
@@ -32,7 +32,7 @@ fn build_dns_query_message(domain_name: &str) -> Vec<u8> {
     message.extend_from_slice(&[0, 0]); // NADRR
 
     // Domain name
-    for (usize, str) in domain_name.split(".").into_iter().enumerate() {
+    for str in domain_name.split('.') {
         message.push(str.len() as u8);
         message.extend_from_slice(str.as_bytes());
         println!("len: {}", str.len());
@@ -48,5 +48,5 @@ fn build_dns_query_message(domain_name: &str) -> Vec<u8> {
 
 fn parse_dns_response(response: &[u8]) -> [u8; 4] {
     println!("response: {:?}", response);
-    return [0, 0, 0, 0];
+    [0, 0, 0, 0]
 }
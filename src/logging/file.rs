@@ -0,0 +1,173 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Result, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// When the log file is rotated: the current file becomes `<path>.1`, `<path>.1` becomes `<path>.2`
+/// and so on, files beyond `keep` are deleted.
+#[derive(Debug, Copy, Clone)]
+pub struct Rotation {
+    /// Rotate before the file grows past this many bytes, 0 for no limit.
+    pub max_size: u64,
+    /// Rotate once the file was written for this long, counted from opening it.
+    pub max_age: Option<Duration>,
+    /// Number of rotated files kept next to the current one.
+    pub keep: usize,
+    /// Compress rotated files into `<path>.<n>.gz`.
+    pub gzip: bool,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_size: 16 * 1024 * 1024,
+            max_age: None,
+            keep: 2,
+            gzip: false,
+        }
+    }
+}
+
+impl Rotation {
+    /// Append to the file forever.
+    pub fn never() -> Self {
+        Self { max_size: 0, max_age: None, keep: 0, gzip: false }
+    }
+}
+
+// The log file shared by all clones of a `Logging`, so a rotation is seen by all of them.
+// Also holds packet captures, which start every file with `header`
+pub(crate) struct Sink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    rotation: Rotation,
+    header: Vec<u8>,
+    // Compression of `<path>.1`, finished before the next rotation moves it
+    compressing: Option<thread::JoinHandle<()>>,
+}
+
+impl Sink {
+    pub fn open(path: &str, rotation: Rotation) -> Result<Self> {
+        Self::with_header(path, rotation, Vec::new())
+    }
+
+    // Appending `header` to an existing file, which must allow for that
+    pub fn with_header(path: &str, rotation: Rotation, header: Vec<u8>) -> Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(&header)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: PathBuf::from(path),
+            file,
+            size,
+            opened: Instant::now(),
+            rotation,
+            header,
+            compressing: None,
+        })
+    }
+
+    // Losing a line beats taking the tunnel down, errors are ignored
+    pub fn write_line(&mut self, line: &str) {
+        self.write(&[line.as_bytes(), b"\n"]);
+    }
+
+    // `parts` go into the same file, rotated before when they would not fit
+    pub fn write(&mut self, parts: &[&[u8]]) {
+        let len = parts.iter().map(|part| part.len() as u64).sum();
+        if self.due(len) {
+            self.rotate().unwrap_or(());
+        }
+        for part in parts {
+            if self.file.write_all(part).is_err() {
+                return;
+            }
+            self.size += part.len() as u64;
+        }
+    }
+
+    fn due(&self, len: u64) -> bool {
+        let Rotation { max_size, max_age, .. } = self.rotation;
+        let full = max_size > 0 && self.size > self.header.len() as u64 && self.size + len > max_size;
+        let old = max_age.is_some_and(|max_age| self.opened.elapsed() >= max_age);
+        full || old
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if let Some(compressing) = self.compressing.take() {
+            compressing.join().unwrap_or(());
+        }
+
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            remove(&self.path)?;
+        } else {
+            remove(&self.rotated(keep, false))?;
+            remove(&self.rotated(keep, true))?;
+            for n in (1..keep).rev() {
+                for gzip in [false, true] {
+                    rename(&self.rotated(n, gzip), &self.rotated(n + 1, gzip))?;
+                }
+            }
+            rename(&self.path, &self.rotated(1, false))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.file.write_all(&self.header)?;
+        self.size = self.header.len() as u64;
+        self.opened = Instant::now();
+
+        if keep > 0 && self.rotation.gzip {
+            let (src, dst) = (self.rotated(1, false), self.rotated(1, true));
+            self.compressing = Some(thread::spawn(move || {
+                if gzip(&src, &dst).is_ok() {
+                    fs::remove_file(&src).unwrap_or(());
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize, gzip: bool) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        if gzip {
+            path.push(".gz");
+        }
+        PathBuf::from(path)
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if let Some(compressing) = self.compressing.take() {
+            compressing.join().unwrap_or(());
+        }
+    }
+}
+
+fn gzip(src: &Path, dst: &Path) -> Result<()> {
+    let mut encoder = GzEncoder::new(File::create(dst)?, Compression::default());
+    io::copy(&mut File::open(src)?, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn rename(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
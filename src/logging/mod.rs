@@ -1,46 +1,159 @@
-use std::fs::{File, OpenOptions};
-use std::io::Write;
-use std::time::{Duration, Instant, SystemTime};
+use std::ffi::{c_char, CStr, CString};
+use std::fmt::{self, Display, Write as _};
+use std::io::Result;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::util::json_string;
+
+pub(crate) use file::Sink;
+pub use file::Rotation;
+
+mod file;
+
+/// Receives every log line: `level` is a `LogLevel`, `tag` and `message` are nul-terminated
+/// and only valid during the call. Called from the tun2socks thread, pass NULL to stop forwarding.
+pub type LogCallback = Option<extern "C" fn(level: c_int, tag: *const c_char, message: *const c_char)>;
+
+/// Severity of a log line, the values match the Android log priorities.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Per-packet details and hexdumps.
+    Trace = 2,
+    Debug = 3,
+    Info = 4,
+    Warn = 5,
+    Error = 6,
+    /// Only as a module level: nothing is logged.
+    Silent = 8,
+}
+
+/// Part of tun2socks a log line comes from, each has its own level.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Module {
+    /// The interface: packets read and written, start and stop.
+    Tun = 0,
+    Tcp = 1,
+    Udp = 2,
+    Dns = 3,
+    Socks5 = 4,
+    /// The flow table: overflow, eviction and idle timeouts.
+    Pool = 5,
+    Icmp = 6,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LogFormat {
+    /// `{elapsed:?} {level}: {message} key=value ...`
+    Text = 0,
+    /// One JSON object per line.
+    Json = 1,
+}
+
+const MODULES: [Module; 7] = [Module::Tun, Module::Tcp, Module::Udp, Module::Dns, Module::Socks5, Module::Pool, Module::Icmp];
+const DEFAULT_LEVEL: LogLevel = LogLevel::Info;
+
+static CALLBACK: RwLock<LogCallback> = RwLock::new(None);
+static LEVELS: [AtomicU8; MODULES.len()] = [const { AtomicU8::new(DEFAULT_LEVEL as u8) }; MODULES.len()];
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Forward log lines to `callback` from now on, `None` stops forwarding.
+pub fn set_callback(callback: LogCallback) {
+    *CALLBACK.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = callback;
+}
+
+/// Log lines of `module` at `level` and above, takes effect immediately.
+pub fn set_level(module: Module, level: LogLevel) {
+    LEVELS[module as usize].store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+/// Log through `logging` when `level` is enabled for `module`, the message is only formatted then.
+///
+/// `log!(logging, Debug, Tcp, "connect to {addr}")`, fields go before a `;`:
+/// `log!(logging, Debug, Tcp, flow = id; "connect to {addr}")`.
+macro_rules! log {
+    ($logging:expr, $level:ident, $module:ident, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if $crate::logging::enabled($crate::logging::Module::$module, $crate::logging::LogLevel::$level) {
+            $logging.log(
+                $crate::logging::Module::$module,
+                $crate::logging::LogLevel::$level,
+                &[$((stringify!($key), &$value as &dyn std::fmt::Display)),+],
+                format_args!($($arg)+),
+            );
+        }
+    };
+    ($logging:expr, $level:ident, $module:ident, $($arg:tt)+) => {
+        if $crate::logging::enabled($crate::logging::Module::$module, $crate::logging::LogLevel::$level) {
+            $logging.log($crate::logging::Module::$module, $crate::logging::LogLevel::$level, &[], format_args!($($arg)+));
+        }
+    };
+}
+pub(crate) use log;
+
+pub fn enabled(module: Module, level: LogLevel) -> bool {
+    level as u8 >= LEVELS[module as usize].load(Ordering::Relaxed)
+}
 
 // #[derive(Copy, Clone)]
 pub struct Logging {
-    file: File,
+    // Shared by all clones, so cloning cannot fail and rotation switches the file for all of them
+    file: Option<Arc<Mutex<Sink>>>,
     instant: Instant,
 }
 
 impl Logging {
-    pub fn new(path: &str) -> Self {
-        let file = OpenOptions::new().create(true).append(true).open(path).unwrap();
-        Logging { file, instant: Instant::now() }
-    }
-
-    fn writeln(&mut self, level: &str, content: String) {
-        let elapsed = self.elapsed();
-        writeln!(&mut self.file, "{:?} {level}: {}", elapsed, content).unwrap();
+    /// Log into the file at `path` as well as to the callback, if one is set.
+    pub fn new(path: &str) -> Result<Self> {
+        Self::with_rotation(path, Rotation::never())
     }
 
-    pub fn v(&mut self, content: String) {
-        self.writeln("V", content);
+    /// Like `new`, rotating the file as `rotation` says.
+    pub fn with_rotation(path: &str, rotation: Rotation) -> Result<Self> {
+        let sink = Sink::open(path, rotation)?;
+        Ok(Logging { file: Some(Arc::new(Mutex::new(sink))), instant: Instant::now() })
     }
 
-    pub fn d(&mut self, content: String) {
-        self.writeln("D", content);
+    /// Log to the callback only.
+    pub fn without_file() -> Self {
+        Logging { file: None, instant: Instant::now() }
     }
 
-    pub fn i(&mut self, content: String) {
-        self.writeln("I", content);
-    }
+    /// Write one line regardless of the levels, use `log!` instead.
+    pub fn log(&self, module: Module, level: LogLevel, fields: &[(&str, &dyn Display)], args: fmt::Arguments) {
+        let callback = *CALLBACK.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if self.file.is_none() && callback.is_none() {
+            return;
+        }
 
-    pub fn w(&mut self, content: String) {
-        self.writeln("W", content);
-    }
+        let elapsed = self.elapsed();
+        let line = if JSON.load(Ordering::Relaxed) {
+            json_line(elapsed, module, level, fields, args)
+        } else {
+            text_line(fields, args)
+        };
 
-    pub fn e(&mut self, content: String) {
-        self.writeln("E", content);
-    }
+        if let Some(file) = &self.file {
+            let mut sink = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if JSON.load(Ordering::Relaxed) {
+                sink.write_line(&line);
+            } else {
+                sink.write_line(&format!("{:?} {} {}: {line}", elapsed, level.letter(), module.name()));
+            }
+        }
 
-    fn now() -> SystemTime {
-        SystemTime::now()
+        if let Some(callback) = callback {
+            let message = CString::new(line.replace('\0', " ")).unwrap_or_default();
+            callback(level as c_int, module.tag().as_ptr(), message.as_ptr());
+        }
     }
 
     fn elapsed(&self) -> Duration {
@@ -52,11 +165,95 @@ impl Logging {
 
 impl Clone for Logging {
     fn clone(&self) -> Self {
-        let f = self.file.try_clone().unwrap();
         Self {
-            file: f,
-            instant: self.instant.clone(),
+            file: self.file.clone(),
+            instant: self.instant,
         }
     }
 }
 
+impl LogLevel {
+    pub fn from_raw(level: c_int) -> Option<Self> {
+        [LogLevel::Trace, LogLevel::Debug, LogLevel::Info, LogLevel::Warn, LogLevel::Error, LogLevel::Silent]
+            .into_iter()
+            .find(|l| *l as c_int == level)
+    }
+
+    fn letter(self) -> &'static str {
+        match self {
+            LogLevel::Trace => "T",
+            LogLevel::Debug => "D",
+            LogLevel::Info => "I",
+            LogLevel::Warn => "W",
+            LogLevel::Error => "E",
+            LogLevel::Silent => "S",
+        }
+    }
+}
+
+impl Module {
+    pub fn from_raw(module: c_int) -> Option<Self> {
+        MODULES.into_iter().find(|m| *m as c_int == module)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Module::Tun => "tun",
+            Module::Tcp => "tcp",
+            Module::Udp => "udp",
+            Module::Dns => "dns",
+            Module::Socks5 => "socks5",
+            Module::Pool => "pool",
+            Module::Icmp => "icmp",
+        }
+    }
+
+    // Tag handed to the callback
+    fn tag(self) -> &'static CStr {
+        match self {
+            Module::Tun => c"tun2socks/tun",
+            Module::Tcp => c"tun2socks/tcp",
+            Module::Udp => c"tun2socks/udp",
+            Module::Dns => c"tun2socks/dns",
+            Module::Socks5 => c"tun2socks/socks5",
+            Module::Pool => c"tun2socks/pool",
+            Module::Icmp => c"tun2socks/icmp",
+        }
+    }
+}
+
+/// Bytes shown as a hexdump, 16 per row, only formatted when the line is logged.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (row, chunk) in self.0.chunks(16).enumerate() {
+            write!(f, "\n{:04x}:", row * 16)?;
+            for byte in chunk {
+                write!(f, " {byte:02x}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn text_line(fields: &[(&str, &dyn Display)], args: fmt::Arguments) -> String {
+    let mut line = args.to_string();
+    for (key, value) in fields {
+        write!(line, " {key}={value}").unwrap_or(());
+    }
+    line
+}
+
+fn json_line(elapsed: Duration, module: Module, level: LogLevel, fields: &[(&str, &dyn Display)], args: fmt::Arguments) -> String {
+    let mut line = format!(r#"{{"elapsed":{:.6},"level":"{}","module":"{}","message":"#, elapsed.as_secs_f64(), level.letter(), module.name());
+    json_string(&mut line, &args.to_string());
+    for (key, value) in fields {
+        line.push(',');
+        json_string(&mut line, key);
+        line.push(':');
+        json_string(&mut line, &value.to_string());
+    }
+    line.push('}');
+    line
+}
@@ -1,26 +1,150 @@
-use std::ffi::CStr;
-use std::os::fd::AsRawFd;
+use std::env;
+use std::ffi::{c_char, CStr};
+use std::os::fd::{IntoRawFd, RawFd};
+use std::os::raw::c_int;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use tun2socks_rust::logging::{self, LogLevel};
 use tun2socks_rust::tun;
-use t::Configuration;
-use t::platform::Device;
+#[cfg(target_os = "linux")]
+use tun2socks_rust::device::{Device, Options};
+#[cfg(not(target_os = "linux"))]
+use t::Device as _;
+
+use cli::system::Setup;
+use cli::{Args, Command, Settings};
 
+#[cfg(not(target_os = "linux"))]
 extern crate tun as t;
 
-fn entry(){
-    let mut config = Configuration::default();
-    config.address((10,0,0,1))
-        .destination((10,0,0,9))
-        .netmask((255,255,255,0))
-        .up();
+mod cli;
+
+const STOP_DEADLINE: Duration = Duration::from_secs(5);
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("tun2socks: {err}\n\n{}", cli::USAGE);
+            process::exit(2);
+        }
+    };
 
-    let mut dev = Device::new(&config).unwrap();
-    // dev.set_nonblock().unwrap();
-    tun::main(dev.as_raw_fd(), CStr::from_bytes_with_nul(b"build/logging.txt\0").unwrap().as_ptr());
+    let result = match args.command {
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            Ok(())
+        }
+        Command::Version => {
+            println!("tun2socks {}", env!("CARGO_PKG_VERSION"));
+            Ok(())
+        }
+        Command::CheckConfig => check_config(&args),
+        Command::Run => run(&args),
+    };
+    if let Err(err) = result {
+        eprintln!("tun2socks: {err}");
+        process::exit(1);
+    }
 }
 
+fn check_config(args: &Args) -> Result<(), String> {
+    let settings = args.settings()?;
+    settings.config()?;
+    print!("{}", settings.to_toml());
+    Ok(())
+}
 
-fn main() {
-    // dns_resolve();
-    // test_datagram_tcp()
-    entry();
+fn run(args: &Args) -> Result<(), String> {
+    let settings = args.settings()?;
+    let config = settings.config()?;
+    for (module, level) in settings.levels()? {
+        logging::set_level(module, level);
+    }
+    logging::set_format(settings.format()?);
+    logging::set_callback(Some(log_to_stderr));
+
+    let (name, fds) = create_device(&settings)?;
+    let proxy = config.proxy.as_ref().map(|proxy| proxy.addr);
+    let setup = match args.setup_system {
+        true => Some(Setup::apply(&name, &settings.system, proxy)?),
+        false => None,
+    };
+
+    // Only the thread waiting for them gets SIGINT and SIGTERM, the spawned threads inherit the mask
+    let signals = block_signals();
+    let mut handle = tun::start_queues(&fds, settings.log.file.as_deref(), config).map_err(|err| err.to_string())?;
+    let stopper = handle.detached();
+    thread::spawn(move || {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        if let Err(err) = stopper.stop(STOP_DEADLINE) {
+            eprintln!("tun2socks: stop: {err}");
+            process::exit(1);
+        }
+    });
+
+    handle.join();
+    drop(setup);
+    Ok(())
+}
+
+// The TUN device, up and addressed: its name and one fd per queue
+#[cfg(target_os = "linux")]
+fn create_device(settings: &Settings) -> Result<(String, Vec<RawFd>), String> {
+    let tun = &settings.tun;
+    let options = Options {
+        name: tun.name.clone(),
+        address: tun.address,
+        netmask: tun.netmask,
+        destination: tun.destination,
+        mtu: tun.mtu,
+        queues: tun.queues,
+        vnet_hdr: tun.vnet_hdr,
+    };
+    let dev = Device::create(&options).map_err(|err| format!("create tun device: {err}"))?;
+    let name = dev.name().to_string();
+    Ok((name, dev.into_queues().into_iter().map(IntoRawFd::into_raw_fd).collect()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_device(settings: &Settings) -> Result<(String, Vec<RawFd>), String> {
+    let tun = &settings.tun;
+    let mut config = t::Configuration::default();
+    config.address(tun.address)
+        .netmask(tun.netmask)
+        .destination(tun.destination)
+        .mtu(tun.mtu as i32)
+        .up();
+    if let Some(name) = &tun.name {
+        config.name(name);
+    }
+    let dev = t::platform::Device::new(&config).map_err(|err| format!("create tun device: {err}"))?;
+    let name = dev.name().map_err(|err| format!("tun device name: {err}"))?;
+    Ok((name, vec![dev.into_raw_fd()]))
+}
+
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+        signals
+    }
+}
+
+extern "C" fn log_to_stderr(level: c_int, tag: *const c_char, message: *const c_char) {
+    let (tag, message) = unsafe { (CStr::from_ptr(tag), CStr::from_ptr(message)) };
+    let level = match LogLevel::from_raw(level) {
+        Some(LogLevel::Trace) => "TRACE",
+        Some(LogLevel::Debug) => "DEBUG",
+        Some(LogLevel::Warn) => "WARN",
+        Some(LogLevel::Error) => "ERROR",
+        _ => "INFO",
+    };
+    eprintln!("{level} {}: {}", tag.to_string_lossy(), message.to_string_lossy());
 }
@@ -0,0 +1,164 @@
+use std::fmt::Write as _;
+use std::io::{Read, Result, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::logging::{log, Logging};
+use crate::stats::{Snapshot, Stats};
+
+// Slow or silent scrapers must not hold up the next one for long
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Serves the counters of one instance in the Prometheus text format at `/metrics`, on its own thread.
+pub struct Exporter {
+    addr: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Exporter {
+    /// Listen on `127.0.0.1:port`, port 0 picks a free one, see `addr`.
+    pub fn start(port: u16, stats: Arc<Stats>, max_flows: usize, logging: Logging) -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+        let addr = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        log!(logging, Info, Tun, "metrics on http://{addr}/metrics");
+
+        let thread = thread::Builder::new().name("tun2socks-metrics".to_string()).spawn({
+            let stopped = Arc::clone(&stopped);
+            move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let result = stream.and_then(|stream| respond(stream, &stats, max_flows));
+                    if let Err(err) = result {
+                        log!(logging, Debug, Tun, "metrics request error: {err}");
+                    }
+                }
+            }
+        })?;
+
+        Ok(Self { addr, stopped, thread: Some(thread) })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Close the listener and join its thread.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stopped.store(true, Ordering::Relaxed);
+            // Wake the blocking accept
+            TcpStream::connect(self.addr).map(|_| ()).unwrap_or(());
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// One request per connection, answered and closed
+fn respond(mut stream: TcpStream, stats: &Stats, max_flows: usize) -> Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let method = words.next().unwrap_or("");
+    let path = words.next().unwrap_or("").split('?').next().unwrap_or("");
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", render(&stats.snapshot(), max_flows)),
+        (_, "/metrics") => ("405 Method Not Allowed", String::new()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    stream.shutdown(Shutdown::Both).unwrap_or(());
+    Ok(())
+}
+
+/// The snapshot in the Prometheus text exposition format.
+pub fn render(snapshot: &Snapshot, max_flows: usize) -> String {
+    let s = snapshot;
+    let mut out = String::new();
+    metric(&mut out, "tun2socks_packets_total", "counter", "IP packets read from (up) and written to (down) the interface.",
+        &[("direction=\"up\"", s.packets_up), ("direction=\"down\"", s.packets_down)]);
+    metric(&mut out, "tun2socks_bytes_total", "counter", "TCP and UDP payload sent to (up) and received from (down) remotes.",
+        &[("direction=\"up\"", s.bytes_up), ("direction=\"down\"", s.bytes_down)]);
+    metric(&mut out, "tun2socks_flows", "gauge", "Flows in the flow table.",
+        &[("protocol=\"tcp\"", s.tcp_flows), ("protocol=\"udp\"", s.udp_flows), ("protocol=\"icmp\"", s.icmp_flows)]);
+    metric(&mut out, "tun2socks_max_flows", "gauge", "Size of the flow table.", &[("", max_flows as u64)]);
+    metric(&mut out, "tun2socks_connect_attempts_total", "counter", "Upstream connections and sessions opened.", &[("", s.connect_attempts)]);
+    metric(&mut out, "tun2socks_connect_failures_total", "counter", "Upstream connections and sessions that failed to open.", &[("", s.connect_failures)]);
+    metric(&mut out, "tun2socks_dns_queries_total", "counter", "UDP datagrams to port 53.", &[("", s.dns_queries)]);
+    metric(&mut out, "tun2socks_evictions_total", "counter", "Flows closed to make room for new ones.", &[("", s.evictions)]);
+    metric(&mut out, "tun2socks_idle_timeouts_total", "counter", "Flows closed by their idle timeout.", &[("", s.idle_timeouts)]);
+    metric(&mut out, "tun2socks_drops_total", "counter", "Dropped packets by reason.", &[
+        ("reason=\"malformed\"", s.drops_malformed),
+        ("reason=\"unsupported_version\"", s.drops_unsupported_version),
+        ("reason=\"unsupported_protocol\"", s.drops_unsupported_protocol),
+        ("reason=\"no_flow\"", s.drops_no_flow),
+        ("reason=\"flow_table_full\"", s.drops_flow_table_full),
+        ("reason=\"write_error\"", s.drops_write_error),
+        ("reason=\"bad_checksum\"", s.drops_bad_checksum),
+        ("reason=\"ttl_exceeded\"", s.drops_ttl_exceeded),
+    ]);
+    metric(&mut out, "tun2socks_dns_cache_lookups_total", "counter", "New flows looked up in the DNS cache.",
+        &[("result=\"hit\"", s.dns_cache_hits), ("result=\"miss\"", s.dns_cache_misses)]);
+    metric(&mut out, "tun2socks_dns_cache_entries", "gauge", "Addresses in the DNS cache.", &[("", s.dns_cache_entries)]);
+    if let Some(threads) = threads() {
+        metric(&mut out, "tun2socks_threads", "gauge", "Threads of the process.", &[("", threads)]);
+    }
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, u64)]) {
+    writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}").unwrap_or(());
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            writeln!(out, "{name} {value}").unwrap_or(());
+        } else {
+            writeln!(out, "{name}{{{labels}}} {value}").unwrap_or(());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn threads() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status.lines().find_map(|line| line.strip_prefix("Threads:"))?.trim().parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn threads() -> Option<u64> {
+    None
+}
@@ -0,0 +1,63 @@
+use std::fmt;
+use std::io;
+
+/// Why bytes from the interface or a remote could not be parsed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// `len` bytes where the header or field needs `needed`.
+    Truncated { needed: usize, len: usize },
+    /// IP version other than 4.
+    Version(u8),
+    /// IPv4 header length below 20 bytes.
+    HeaderLength(usize),
+    /// IPv4 total length below the header or above the bytes read.
+    TotalLength { total: usize, len: usize },
+    /// IPv4 fragment other than the first, at this byte offset: it has no transport header.
+    Fragment(usize),
+    /// TCP data offset below 20 bytes.
+    DataOffset(usize),
+    /// TCP option shorter than its kind and length, longer than the options, or unfit for its kind.
+    Option { kind: u8, length: u8 },
+    /// UDP length below its header or above the bytes read.
+    UdpLength { length: usize, len: usize },
+    /// SOCKS5 address type other than IPv4, domain name and IPv6.
+    AddressType(u8),
+    /// DNS name following more compression pointers than allowed.
+    Pointers,
+    /// Well formed, but not the message expected.
+    Unexpected(&'static str),
+}
+
+// At least `needed` bytes in `bytes`
+pub(crate) fn need(bytes: &[u8], needed: usize) -> Result<(), ParseError> {
+    match bytes.len() < needed {
+        true => Err(ParseError::Truncated { needed, len: bytes.len() }),
+        false => Ok(()),
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Truncated { needed, len } => write!(f, "truncated, {len} of {needed} bytes"),
+            ParseError::Version(version) => write!(f, "unsupported version({version})"),
+            ParseError::HeaderLength(len) => write!(f, "header length({len}) below 20"),
+            ParseError::TotalLength { total, len } => write!(f, "total length({total}) does not fit {len} bytes"),
+            ParseError::Fragment(offset) => write!(f, "fragment at offset({offset}) without a transport header"),
+            ParseError::DataOffset(offset) => write!(f, "data offset({offset}) below 20"),
+            ParseError::Option { kind, length } => write!(f, "bad option, kind({kind}), length({length})"),
+            ParseError::UdpLength { length, len } => write!(f, "udp length({length}) does not fit {len} bytes"),
+            ParseError::AddressType(atyp) => write!(f, "unknown address type({atyp})"),
+            ParseError::Pointers => write!(f, "too many compression pointers"),
+            ParseError::Unexpected(what) => write!(f, "unexpected {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(err: ParseError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
@@ -0,0 +1,64 @@
+// The Internet checksum (RFC 1071): the one's complement of the one's complement sum of 16 bit words.
+// Sums are kept folded to 16 bits and not complemented, so they add up; only a sum over an even
+// number of bytes may be followed by another.
+
+// Sum of `bytes` as big endian words, an odd last byte padded with a zero
+pub fn sum(bytes: &[u8]) -> u16 {
+    let mut words = bytes.chunks_exact(2);
+    // 64 bits take any length without folding in between
+    let mut sum: u64 = words.by_ref().map(|word| u16::from_be_bytes([word[0], word[1]]) as u64).sum();
+    if let [last] = words.remainder() {
+        sum += (*last as u64) << 8;
+    }
+    fold(sum)
+}
+
+// One's complement addition of two sums
+pub fn add(a: u16, b: u16) -> u16 {
+    fold(a as u64 + b as u64)
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+// Checksum of `bytes`, the field it goes into counted as zero
+pub fn checksum(bytes: &[u8]) -> u16 {
+    !sum(bytes)
+}
+
+// Whether `bytes`, their checksum field included, sum up to all ones
+pub fn verify(bytes: &[u8]) -> bool {
+    sum(bytes) == 0xFFFF
+}
+
+// Sum of the pseudo header of a TCP or UDP segment, `src` and `dst` are 4 (IPv4) or 16 (IPv6) bytes
+pub fn pseudo_header_sum(src: &[u8], dst: &[u8], protocol: u8, length: usize) -> u16 {
+    let length = length as u64;
+    fold(sum(src) as u64 + sum(dst) as u64 + protocol as u64 + (length >> 16) + (length & 0xFFFF))
+}
+
+// Checksum of a TCP or UDP `segment` between `src` and `dst`, its checksum field counted as zero
+pub fn transport(src: &[u8], dst: &[u8], protocol: u8, segment: &[u8]) -> u16 {
+    !add(pseudo_header_sum(src, dst, protocol, segment.len()), sum(segment))
+}
+
+// Whether a TCP or UDP `segment` between `src` and `dst` carries a valid checksum
+pub fn verify_transport(src: &[u8], dst: &[u8], protocol: u8, segment: &[u8]) -> bool {
+    add(pseudo_header_sum(src, dst, protocol, segment.len()), sum(segment)) == 0xFFFF
+}
+
+// `checksum` after a 16 bit word it covers changed from `old` to `new`, without summing the rest
+// again: HC' = ~(~HC + ~m + m') of RFC 1624, which never yields the -0 of RFC 1141
+pub fn update(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold(!checksum as u64 + !old as u64 + new as u64)
+}
+
+// `update` for a 32 bit field such as an IPv4 address
+pub fn update_u32(checksum: u16, old: u32, new: u32) -> u16 {
+    let checksum = update(checksum, (old >> 16) as u16, (new >> 16) as u16);
+    update(checksum, old as u16, new as u16)
+}
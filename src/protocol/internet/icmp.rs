@@ -1,39 +1,90 @@
-use std::net::SocketAddr;
-use crate::protocol::internet::{Datagram, Packet, Protocol};
-use crate::util::bytes_to_u32;
+use std::any::Any;
+use std::fmt::Debug;
+use std::net::Ipv4Addr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::protocol::internet::{checksum, Packet, Protocol};
+use crate::protocol::{need, ParseError};
+
+/*
+                      ICMP Message Format (RFC 792)
+
+                  0      7 8     15 16    23 24    31
+                 +--------+--------+--------+--------+
+                 |  Type  |  Code  |    Checksum     |
+                 +--------+--------+--------+--------+
+                 |      Rest of the header, by type  |
+                 +--------+--------+--------+--------+
+                 |                                   :
+                 :   Data, for an error the internet :
+                 :   header + 64 bits of the datagram:
+                 :   it is about                     |
+                 +-----------------------------------+
+
+Each type has an `IcmpEntity` for everything after the checksum.
+ */
 pub struct Icmp {
     header: Header,
     payload: Vec<u8>,
     entity: Box<dyn IcmpEntity + Send + Sync>,
 }
 
+#[allow(dead_code)]
 struct Header {
     tp: u8,
     code: u8,
     checksum: [u8; 2],
 }
 
-pub trait IcmpEntity {
+// The message after type, code and checksum
+pub trait IcmpEntity: Any + Debug {
     fn pack(&self) -> Vec<u8>;
 }
 
 impl Icmp {
-    pub fn new(bytes: &[u8]) -> Self {
+    // Every message has 4 bytes after the type, code and checksum
+    pub fn new(bytes: &[u8]) -> Result<Self, ParseError> {
+        need(bytes, 8)?;
         let header = Header {
             tp: bytes[0],
             code: bytes[1],
             checksum: [bytes[2], bytes[3]],
         };
 
-        let payload = (&bytes[4..]).to_vec();
-        let entity = Box::new(Echo::new(&bytes[4..]));
+        let rest = &bytes[4..];
+        let entity: Box<dyn IcmpEntity + Send + Sync> = match header.tp {
+            ECHO_REPLY | ECHO_REQUEST => Box::new(Echo::new(rest)?),
+            DESTINATION_UNREACHABLE => Box::new(Unreachable::new(rest)?),
+            SOURCE_QUENCH => Box::new(SourceQuench::new(rest)?),
+            REDIRECT => Box::new(Redirect::new(rest)?),
+            ROUTER_ADVERTISEMENT => Box::new(RouterAdvertisement::new(rest)?),
+            ROUTER_SOLICITATION => Box::new(RouterSolicitation),
+            TIME_EXCEEDED => Box::new(TimeExceeded::new(rest)?),
+            PARAMETER_PROBLEM => Box::new(ParameterProblem::new(rest)?),
+            TIMESTAMP_REQUEST | TIMESTAMP_REPLY => Box::new(Timestamp::new(rest)?),
+            INFORMATION_REQUEST | INFORMATION_REPLY => Box::new(Information::new(rest)?),
+            ADDRESS_MASK_REQUEST | ADDRESS_MASK_REPLY => Box::new(AddressMask::new(rest)?),
+            _ => Box::new(Other { data: rest.to_vec() }),
+        };
 
-        Self {
+        Ok(Self {
             header,
-            payload,
+            payload: rest.to_vec(),
             entity,
-        }
+        })
+    }
+
+    pub fn tp(&self) -> u8 {
+        self.header.tp
+    }
+
+    pub fn code(&self) -> u8 {
+        self.header.code
+    }
+
+    // The message as `T`, the entity type of its ICMP type
+    pub fn entity<T: IcmpEntity>(&self) -> Option<&T> {
+        (&*self.entity as &dyn Any).downcast_ref()
     }
 }
 
@@ -41,77 +92,428 @@ impl Packet for Icmp {
     fn protocol(&self) -> Protocol {
         Protocol::ICMP
     }
-   
+
     fn payload(&self) -> &Vec<u8> {
         &self.payload
     }
 
     fn info(&self) -> String {
-        String::new()
+        let mut info = String::new();
+        info.push_str("ICMP info:\n");
+        info.push_str(&format!("\ttype: {}, code: {}\n", self.header.tp, self.header.code));
+        info.push_str(&format!("\t{:?}\n", self.entity));
+        info
     }
 
-    fn pack(&self, options: &[u8], payload: &[u8]) -> Vec<u8> {
-        let mut packet = Vec::new();
-        packet.push(0);
-        packet.push(self.header.code);
-        packet.extend_from_slice(&[0, 0]);
-        packet.extend_from_slice(&self.entity.pack());
-
-        let checksum = if packet.len() % 2 != 0 {
-            packet.push(0);
-            let result = Datagram::calc_checksum(&packet);
-            packet.pop().unwrap();
-            result
-        } else {
-            Datagram::calc_checksum(&packet)
-        };
+    fn echo(&self) -> Option<(u16, u16)> {
+        match (self.header.tp, self.header.code) {
+            (ECHO_REQUEST, 0) => self.entity::<Echo>().map(|echo| (echo.id, echo.seq)),
+            _ => None,
+        }
+    }
+
+    // The reply to a request, any other message as it is
+    fn pack(&self, _options: &[u8], _payload: &[u8]) -> Vec<u8> {
+        let code = self.header.code;
+        match self.header.tp {
+            ECHO_REQUEST => message(ECHO_REPLY, code, &*self.entity),
+            INFORMATION_REQUEST => message(INFORMATION_REPLY, code, &*self.entity),
+            // The mask asked for is not known here, the request's goes back
+            ADDRESS_MASK_REQUEST => message(ADDRESS_MASK_REPLY, code, &*self.entity),
+            TIMESTAMP_REQUEST => match self.entity::<Timestamp>() {
+                Some(request) => {
+                    let now = Timestamp::now();
+                    message(TIMESTAMP_REPLY, code, &Timestamp { receive: now, transmit: now, ..*request })
+                }
+                None => message(TIMESTAMP_REPLY, code, &*self.entity),
+            },
+            tp => message(tp, code, &*self.entity),
+        }
+    }
+}
 
-        (packet[2], packet[3]) = (checksum[0], checksum[1]);
+/// A message of type `tp` and `code` carrying `entity`, its checksum set.
+pub fn message(tp: u8, code: u8, entity: &dyn IcmpEntity) -> Vec<u8> {
+    let mut packet = vec![tp, code, 0, 0];
+    packet.extend_from_slice(&entity.pack());
+    set_checksum(&mut packet);
+    packet
+}
 
-        packet
+/// Destination unreachable message, `origin` is the internet header + 64 bits of the offending datagram.
+pub fn destination_unreachable(code: u8, origin: &[u8]) -> Vec<u8> {
+    message(DESTINATION_UNREACHABLE, code, &Unreachable { next_hop_mtu: 0, origin: origin.to_vec() })
+}
+
+/// Time exceeded message, `origin` as for `destination_unreachable`.
+pub fn time_exceeded(code: u8, origin: &[u8]) -> Vec<u8> {
+    message(TIME_EXCEEDED, code, &TimeExceeded { origin: origin.to_vec() })
+}
+
+/// Parameter problem message, `pointer` is the offset of the bad byte in `origin`.
+pub fn parameter_problem(pointer: u8, origin: &[u8]) -> Vec<u8> {
+    message(PARAMETER_PROBLEM, 0, &ParameterProblem { pointer, origin: origin.to_vec() })
+}
+
+/// Identifier and sequence number of an echo request, `bytes` being the ICMP message.
+pub fn echo_request(bytes: &[u8]) -> Option<(u16, u16)> {
+    match bytes {
+        [ECHO_REQUEST, 0, _, _, id0, id1, seq0, seq1, ..] => Some((u16::from_be_bytes([*id0, *id1]), u16::from_be_bytes([*seq0, *seq1]))),
+        _ => None,
     }
 }
 
+/// Put identifier `id` into the echo message `packet`, its checksum updated to match.
+pub fn set_echo_id(packet: &mut [u8], id: u16) {
+    let old = u16::from_be_bytes([packet[4], packet[5]]);
+    let sum = checksum::update(u16::from_be_bytes([packet[2], packet[3]]), old, id);
+    packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    packet[4..6].copy_from_slice(&id.to_be_bytes());
+}
+
+fn set_checksum(packet: &mut [u8]) {
+    let checksum = checksum::checksum(packet);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+// Internet header + 64 bits of the datagram an error is about, after the 4 bytes of the header
+fn origin(rest: &[u8]) -> Result<Vec<u8>, ParseError> {
+    need(rest, 4 + 20)?;
+    Ok(rest[4..].to_vec())
+}
+
+// Identifier and sequence number, then the data echoed back (types 0 and 8)
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Echo {
-    id: [u8; 2],
-    seq: [u8; 2],
-    data: Vec<u8>,
-    len: usize,
+    pub id: u16,
+    pub seq: u16,
+    pub data: Vec<u8>,
 }
 
 impl Echo {
-    fn new(payload: &[u8]) -> Self {
-        Self {
-            id: [payload[0], payload[1]],
-            seq: [payload[2], payload[3]],
-            data: payload[4..].to_vec(),
-            len: payload.len(),
-        }
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        need(rest, 4)?;
+        Ok(Self {
+            id: u16_at(rest, 0),
+            seq: u16_at(rest, 2),
+            data: rest[4..].to_vec(),
+        })
     }
 }
 
 impl IcmpEntity for Echo {
     fn pack(&self) -> Vec<u8> {
         let mut packet = Vec::new();
-        packet.extend_from_slice(&self.id);
-        packet.extend_from_slice(&self.seq);
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&self.seq.to_be_bytes());
         packet.extend_from_slice(&self.data);
         packet
     }
 }
 
-const ECHO_REPLY: u8 = 0;
-const DESTINATION_UNREACHABLE: u8 = 3;
-const SOURCE_QUENCH: u8 = 4;
-const REDIRECT: u8 = 5;
-const ECHO_REQUEST: u8 = 8;
-const ROUTER_ADVERTISEMENT: u8 = 9;
-const ROUTER_SOLICITATION: u8 = 10;
-const TIME_EXCEEDED: u8 = 11;
-const PARAMETER_PROBLEM: u8 = 12;
-const TIMESTAMP_REQUEST: u8 = 13;
-const TIMESTAMP_REPLY: u8 = 14;
-const INFORMATION_REQUEST: u8 = 15;
-const INFORMATION_REPLY: u8 = 16;
-const ADDRESS_MASK_REQUEST: u8 = 17;
-const ADDRESS_MASK_REPLY: u8 = 18;
\ No newline at end of file
+// Unused, the MTU of the next hop for code 4 (RFC 1191), then the origin (type 3)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unreachable {
+    pub next_hop_mtu: u16,
+    pub origin: Vec<u8>,
+}
+
+impl Unreachable {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        let origin = origin(rest)?;
+        Ok(Self { next_hop_mtu: u16_at(rest, 2), origin })
+    }
+}
+
+impl IcmpEntity for Unreachable {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = vec![0, 0];
+        packet.extend_from_slice(&self.next_hop_mtu.to_be_bytes());
+        packet.extend_from_slice(&self.origin);
+        packet
+    }
+}
+
+// Unused, then the origin (type 4)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceQuench {
+    pub origin: Vec<u8>,
+}
+
+impl SourceQuench {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        Ok(Self { origin: origin(rest)? })
+    }
+}
+
+impl IcmpEntity for SourceQuench {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = vec![0; 4];
+        packet.extend_from_slice(&self.origin);
+        packet
+    }
+}
+
+// The gateway to send to instead, then the origin (type 5)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub gateway: Ipv4Addr,
+    pub origin: Vec<u8>,
+}
+
+impl Redirect {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        let origin = origin(rest)?;
+        Ok(Self { gateway: Ipv4Addr::from(u32_at(rest, 0)), origin })
+    }
+}
+
+impl IcmpEntity for Redirect {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = self.gateway.octets().to_vec();
+        packet.extend_from_slice(&self.origin);
+        packet
+    }
+}
+
+// Number of addresses, words per entry, lifetime in seconds, then address and preference each
+// (type 9, RFC 1256). Words beyond the two known ones are skipped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterAdvertisement {
+    pub lifetime: u16,
+    pub routers: Vec<(Ipv4Addr, i32)>,
+}
+
+impl RouterAdvertisement {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        let (count, words) = (rest[0] as usize, rest[1] as usize);
+        if words < 2 {
+            return Err(ParseError::Unexpected("router entry size"));
+        }
+        need(rest, 4 + count * words * 4)?;
+        let routers = (0..count)
+            .map(|i| 4 + i * words * 4)
+            .map(|at| (Ipv4Addr::from(u32_at(rest, at)), u32_at(rest, at + 4) as i32))
+            .collect();
+        Ok(Self { lifetime: u16_at(rest, 2), routers })
+    }
+}
+
+impl IcmpEntity for RouterAdvertisement {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = vec![self.routers.len() as u8, 2];
+        packet.extend_from_slice(&self.lifetime.to_be_bytes());
+        for (addr, preference) in &self.routers {
+            packet.extend_from_slice(&addr.octets());
+            packet.extend_from_slice(&preference.to_be_bytes());
+        }
+        packet
+    }
+}
+
+// Reserved (type 10)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterSolicitation;
+
+impl IcmpEntity for RouterSolicitation {
+    fn pack(&self) -> Vec<u8> {
+        vec![0; 4]
+    }
+}
+
+// Unused, then the origin (type 11)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeExceeded {
+    pub origin: Vec<u8>,
+}
+
+impl TimeExceeded {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        Ok(Self { origin: origin(rest)? })
+    }
+}
+
+impl IcmpEntity for TimeExceeded {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = vec![0; 4];
+        packet.extend_from_slice(&self.origin);
+        packet
+    }
+}
+
+// Offset of the byte in error, unused, then the origin (type 12)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterProblem {
+    pub pointer: u8,
+    pub origin: Vec<u8>,
+}
+
+impl ParameterProblem {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        let origin = origin(rest)?;
+        Ok(Self { pointer: rest[0], origin })
+    }
+}
+
+impl IcmpEntity for ParameterProblem {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = vec![self.pointer, 0, 0, 0];
+        packet.extend_from_slice(&self.origin);
+        packet
+    }
+}
+
+// Identifier, sequence number, then milliseconds since midnight UT when the request was sent, and
+// when the reply was received and sent (types 13 and 14)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Timestamp {
+    pub id: u16,
+    pub seq: u16,
+    pub originate: u32,
+    pub receive: u32,
+    pub transmit: u32,
+}
+
+impl Timestamp {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        need(rest, 16)?;
+        Ok(Self {
+            id: u16_at(rest, 0),
+            seq: u16_at(rest, 2),
+            originate: u32_at(rest, 4),
+            receive: u32_at(rest, 8),
+            transmit: u32_at(rest, 12),
+        })
+    }
+
+    pub fn now() -> u32 {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        (since_epoch.as_millis() % 86_400_000) as u32
+    }
+}
+
+impl IcmpEntity for Timestamp {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&self.originate.to_be_bytes());
+        packet.extend_from_slice(&self.receive.to_be_bytes());
+        packet.extend_from_slice(&self.transmit.to_be_bytes());
+        packet
+    }
+}
+
+// Identifier and sequence number (types 15 and 16)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Information {
+    pub id: u16,
+    pub seq: u16,
+}
+
+impl Information {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        Ok(Self { id: u16_at(rest, 0), seq: u16_at(rest, 2) })
+    }
+}
+
+impl IcmpEntity for Information {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = self.id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet
+    }
+}
+
+// Identifier, sequence number and the subnet mask (types 17 and 18, RFC 950)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AddressMask {
+    pub id: u16,
+    pub seq: u16,
+    pub mask: Ipv4Addr,
+}
+
+impl AddressMask {
+    fn new(rest: &[u8]) -> Result<Self, ParseError> {
+        need(rest, 8)?;
+        Ok(Self { id: u16_at(rest, 0), seq: u16_at(rest, 2), mask: Ipv4Addr::from(u32_at(rest, 4)) })
+    }
+}
+
+impl IcmpEntity for AddressMask {
+    fn pack(&self) -> Vec<u8> {
+        let mut packet = self.id.to_be_bytes().to_vec();
+        packet.extend_from_slice(&self.seq.to_be_bytes());
+        packet.extend_from_slice(&self.mask.octets());
+        packet
+    }
+}
+
+// Any other type, kept as it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Other {
+    pub data: Vec<u8>,
+}
+
+impl IcmpEntity for Other {
+    fn pack(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+}
+
+pub const ECHO_REPLY: u8 = 0;
+pub const DESTINATION_UNREACHABLE: u8 = 3;
+pub const SOURCE_QUENCH: u8 = 4;
+pub const REDIRECT: u8 = 5;
+pub const ECHO_REQUEST: u8 = 8;
+pub const ROUTER_ADVERTISEMENT: u8 = 9;
+pub const ROUTER_SOLICITATION: u8 = 10;
+pub const TIME_EXCEEDED: u8 = 11;
+pub const PARAMETER_PROBLEM: u8 = 12;
+pub const TIMESTAMP_REQUEST: u8 = 13;
+pub const TIMESTAMP_REPLY: u8 = 14;
+pub const INFORMATION_REQUEST: u8 = 15;
+pub const INFORMATION_REPLY: u8 = 16;
+pub const ADDRESS_MASK_REQUEST: u8 = 17;
+pub const ADDRESS_MASK_REPLY: u8 = 18;
+
+// Destination unreachable codes
+pub const NET_UNREACHABLE: u8 = 0;
+pub const HOST_UNREACHABLE: u8 = 1;
+pub const PROTOCOL_UNREACHABLE: u8 = 2;
+pub const PORT_UNREACHABLE: u8 = 3;
+pub const FRAGMENTATION_NEEDED: u8 = 4;
+pub const SOURCE_ROUTE_FAILED: u8 = 5;
+pub const NET_UNKNOWN: u8 = 6;
+pub const HOST_UNKNOWN: u8 = 7;
+pub const SOURCE_HOST_ISOLATED: u8 = 8;
+pub const NET_PROHIBITED: u8 = 9;
+pub const HOST_PROHIBITED: u8 = 10;
+pub const NET_UNREACHABLE_FOR_TOS: u8 = 11;
+pub const HOST_UNREACHABLE_FOR_TOS: u8 = 12;
+pub const ADMINISTRATIVELY_PROHIBITED: u8 = 13;
+pub const HOST_PRECEDENCE_VIOLATION: u8 = 14;
+pub const PRECEDENCE_CUTOFF: u8 = 15;
+
+// Redirect codes
+pub const REDIRECT_NET: u8 = 0;
+pub const REDIRECT_HOST: u8 = 1;
+pub const REDIRECT_TOS_NET: u8 = 2;
+pub const REDIRECT_TOS_HOST: u8 = 3;
+
+// Time exceeded codes
+pub const TTL_EXCEEDED: u8 = 0;
+pub const REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+
+// Parameter problem codes
+pub const POINTER_INDICATES_ERROR: u8 = 0;
+pub const MISSING_OPTION: u8 = 1;
+pub const BAD_LENGTH: u8 = 2;
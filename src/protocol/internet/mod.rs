@@ -1,13 +1,16 @@
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 use crate::protocol::internet::icmp::Icmp;
 use crate::protocol::internet::tcp::{FlagsType, Tcp};
 use crate::protocol::internet::udp::Udp;
-use crate::util::bytes_to_u32;
+use crate::protocol::internet::view::FlowKey;
+use crate::protocol::{need, ParseError};
 
 pub mod tcp;
 pub mod udp;
 pub mod icmp;
+pub mod view;
+pub mod checksum;
 
 /*
    Internet Header Format
@@ -75,6 +78,8 @@ pub struct Datagram {
     pub header: Header,
     pub pseudo_header: PseudoHeader,
     pub payload: Payload,
+    // Internet header + 64 bits of original data, quoted by ICMP error messages
+    pub origin: Vec<u8>,
 }
 
 pub struct Header {
@@ -114,15 +119,28 @@ pub struct PseudoHeader {
 }
 
 impl Datagram {
-    pub fn new(bytes: &[u8]) -> Self {
-        let ihl = bytes[0] & 0x0F;
-        let options_len = (ihl - 5) as usize;
-        // Self::verify_checksum(&bytes);
+    // Checks the header against `bytes`, the payload ends at the total length
+    pub fn new(bytes: &[u8]) -> Result<Self, ParseError> {
+        need(bytes, 20)?;
+        let version = bytes[0] >> 4;
+        if version != 4 {
+            return Err(ParseError::Version(version));
+        }
+        let header_len = (bytes[0] & 0x0F) as usize * 4;
+        if header_len < 20 {
+            return Err(ParseError::HeaderLength(header_len));
+        }
+        need(bytes, header_len)?;
+        let total = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        if total < header_len || total > bytes.len() {
+            return Err(ParseError::TotalLength { total, len: bytes.len() });
+        }
+        let bytes = &bytes[..total];
 
         let src_ip = [bytes[12], bytes[13], bytes[14], bytes[15]];
         let dst_ip = [bytes[16], bytes[17], bytes[18], bytes[19]];
         let protocol = bytes[9];
-        let payload = bytes[(20 + options_len)..].to_owned();
+        let payload = &bytes[header_len..];
 
         let pseudo_header = PseudoHeader {
             src_ip,
@@ -132,9 +150,14 @@ impl Datagram {
         };
 
         let protocol = Self::get_protocol(protocol);
-        let payload = Self::build_payload(protocol, pseudo_header, &payload);
+        let offset = (u16::from_be_bytes([bytes[6], bytes[7]]) & 0x1FFF) as usize * 8;
+        if offset != 0 && !matches!(protocol, Protocol::UNKNOWN) {
+            return Err(ParseError::Fragment(offset));
+        }
+        let payload = Self::build_payload(protocol, pseudo_header, payload)?;
+        let origin = bytes[..bytes.len().min(header_len + 8)].to_vec();
 
-        Self {
+        Ok(Self {
             header: Header {
                 version_ihl: bytes[0],
                 dscp_ecn: bytes[1],
@@ -146,79 +169,54 @@ impl Datagram {
                 checksum: [bytes[10], bytes[11]],
                 src_ip,
                 dst_ip,
-                options: bytes[20..(20 + options_len)].to_owned(),
+                options: bytes[20..header_len].to_owned(),
             },
             pseudo_header,
             payload: Arc::new(payload),
-        }
+            origin,
+        })
     }
 
-    fn build_payload(protocol: Protocol, pseudo_header: PseudoHeader, bytes: &[u8]) -> Box<dyn Packet + Send + Sync> {
-        match protocol {
+    fn build_payload(protocol: Protocol, pseudo_header: PseudoHeader, bytes: &[u8]) -> Result<Box<dyn Packet + Send + Sync>, ParseError> {
+        Ok(match protocol {
             Protocol::TCP => {
-                Box::new(Tcp::new(bytes, pseudo_header))
+                Box::new(Tcp::new(bytes, pseudo_header)?)
             }
             Protocol::UDP => {
-                Box::new(Udp::new(bytes, pseudo_header))
+                Box::new(Udp::new(bytes, pseudo_header)?)
             }
             Protocol::ICMP => {
-                Box::new(Icmp::new(bytes))
+                Box::new(Icmp::new(bytes)?)
             }
             Protocol::UNKNOWN => {
-                Box::new(Udp::new(bytes, pseudo_header))
+                Box::new(Other { payload: bytes.to_vec() })
             }
-        }
+        })
     }
 
     pub fn protocol(&self) -> Protocol {
-        return match self.header.protocol {
-            1 => Protocol::ICMP,
-            6 => Protocol::TCP,
-            17 => Protocol::UDP,
-            _ => Protocol::UNKNOWN
-        };
+        Self::get_protocol(self.header.protocol)
     }
 
     pub fn get_protocol(byte: u8) -> Protocol {
-        return match byte {
-            1 => Protocol::ICMP,
-            6 => Protocol::TCP,
-            17 => Protocol::UDP,
+        match byte {
+            ICMP => Protocol::ICMP,
+            TCP => Protocol::TCP,
+            UDP => Protocol::UDP,
             _ => Protocol::UNKNOWN
-        };
+        }
     }
 
-    pub fn verify_checksum(header: &Vec<u8>) -> bool {
-        let checksum = Self::calc_checksum(header);
-        return if header[10] != 0 || header[11] != 0 {
-            bytes_to_u32(&checksum) == 0
+    pub fn verify_checksum(header: &[u8]) -> bool {
+        if header[10] != 0 || header[11] != 0 {
+            checksum::verify(header)
         } else {
             false
-        };
+        }
     }
 
     pub fn calc_checksum(header: &[u8]) -> [u8; 2] {
-        let mut binary_u16_segments = vec![];
-        // Merge two u8 to u16
-        for i in (0..header.len()).step_by(2) {
-            let segment = (header[i] as u16) * 256 + header[i + 1] as u16;
-            binary_u16_segments.push(segment);
-        }
-
-        // Calculate the checksum
-        let mut checksum = 0;
-        for segment in binary_u16_segments {
-            let sum: u32 = checksum as u32 + segment as u32;
-            // handle overflow
-            if sum > 0xFFFF {
-                checksum = ((sum & 0xFFFF) + 1) as u16
-            } else {
-                checksum = sum as u16
-            }
-        }
-
-        // Bitwise and to bytes
-        (!checksum).to_be_bytes()
+        checksum::checksum(header).to_be_bytes()
     }
 
     pub fn resp_header(&self) -> Vec<u8> {
@@ -227,7 +225,7 @@ impl Datagram {
 
         packet.extend_from_slice(&[header.version_ihl, header.dscp_ecn, 0, 0]);
         packet.extend_from_slice(&[0, 0, header.flags_fragment_offset[0], header.flags_fragment_offset[1]]);
-        packet.extend_from_slice(&[header.ttl, header.protocol, 0, 0]);
+        packet.extend_from_slice(&[TTL, header.protocol, 0, 0]);
         packet.extend_from_slice(&header.dst_ip);
         packet.extend_from_slice(&header.src_ip);
         packet.extend_from_slice(&header.options);
@@ -248,15 +246,6 @@ impl Datagram {
         let checksum = Self::calc_checksum(&packet[..(packet.len() - payload.len())]);
         (packet[10], packet[11]) = (checksum[0], checksum[1]);
 
-        // Packet information(LittleEndian): flags(u16) and protocol(u16)
-        #[cfg(any(target_os = "macos", target_os = "ios"))]
-        {
-            packet.insert(0, 0);
-            packet.insert(1, 0);
-            packet.insert(2, 0);
-            packet.insert(3, 2); // IPv4
-        }
-
         packet
     }
 
@@ -265,14 +254,47 @@ impl Datagram {
         Self::pack(&header, payload)
     }
 
+    // Reply to the sender of this datagram with an ICMP message
+    pub fn icmp_resp_pack(&self, icmp: &[u8]) -> Vec<u8> {
+        self.icmp_resp_pack_from(self.header.dst_ip, icmp)
+    }
+
+    // `icmp_resp_pack` from a hop on the way rather than the destination
+    pub fn icmp_resp_pack_from(&self, src_ip: [u8; 4], icmp: &[u8]) -> Vec<u8> {
+        let mut header = self.resp_header();
+        header[9] = ICMP;
+        header.truncate(20); // Options belong to the original datagram
+        header[0] = (header[0] & 0xF0) | 5;
+        header[12..16].copy_from_slice(&src_ip);
+        Self::pack(&header, icmp)
+    }
+
+    // Reply to the sender of this UDP datagram from `src`, whichever remote of the session it came from
+    pub fn udp_resp_pack_from(&self, src: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        let mut header = self.resp_header();
+        header[12..16].copy_from_slice(&src.ip().octets());
+        let dst = SocketAddrV4::new(self.header.src_ip.into(), self.payload.src_addr().port());
+        Self::pack(&header, &udp::pack(src, dst, payload))
+    }
+
     pub fn name(&self) -> String {
-        let protocol = self.protocol();
-        let src_addr = self.payload.src_addr();
-        let dst_addr = self.payload.dst_addr();
-        format!("{:?}[{}]=>[{}]", protocol, src_addr, dst_addr)
+        self.key().to_string()
+    }
+
+    // The flow the datagram belongs to. An ICMP echo request has its identifier as source port, the
+    // ports are 0 for anything else but TCP and UDP
+    pub fn key(&self) -> FlowKey {
+        let (src, dst) = match self.protocol() {
+            Protocol::TCP | Protocol::UDP => (self.payload.src_addr(), self.payload.dst_addr()),
+            _ => {
+                let id = self.payload.echo().map_or(0, |(id, _)| id);
+                (SocketAddr::from((self.header.src_ip, id)), SocketAddr::from((self.header.dst_ip, 0)))
+            }
+        };
+        FlowKey { protocol: self.header.protocol, src, dst }
     }
 
-    pub fn update_seq(&mut self, len: u32) {
+    pub fn update_seq(&mut self, _len: u32) {
         // self.payload.update_seq(0);
     }
 }
@@ -290,6 +312,12 @@ impl PseudoHeader {
     }
 }
 
+pub const ICMP: u8 = 1;
+pub const TCP: u8 = 6;
+pub const UDP: u8 = 17;
+// Time to live of the packets written to the interface, whatever the client's was
+pub const TTL: u8 = 64;
+
 #[derive(Debug)]
 pub enum Protocol {
     TCP,
@@ -298,15 +326,42 @@ pub enum Protocol {
     UNKNOWN,
 }
 
+// Payload of a protocol tun2socks does not relay, kept as it is
+pub struct Other {
+    payload: Vec<u8>,
+}
+
+impl Packet for Other {
+    fn protocol(&self) -> Protocol {
+        Protocol::UNKNOWN
+    }
+
+    fn payload(&self) -> &Vec<u8> {
+        &self.payload
+    }
+
+    fn info(&self) -> String {
+        String::new()
+    }
+
+    fn pack(&self, _options: &[u8], payload: &[u8]) -> Vec<u8> {
+        payload.to_vec()
+    }
+}
+
 pub trait Packet {
     fn protocol(&self) -> Protocol;
     fn src_addr(&self) -> SocketAddr { SocketAddr::new([0, 0, 0, 0].into(), 0) }
     fn dst_addr(&self) -> SocketAddr { SocketAddr::new([0, 0, 0, 0].into(), 0) }
     fn payload(&self) -> &Vec<u8>;
-    fn flags_type(&self) -> FlagsType { return FlagsType(0); }
+    fn flags_type(&self) -> FlagsType { FlagsType(0) }
+    // Sequence number of a TCP segment
+    fn seq(&self) -> Option<u32> { None }
+    // Identifier and sequence number of an ICMP echo request
+    fn echo(&self) -> Option<(u16, u16)> { None }
     fn info(&self) -> String;
     fn pack(&self, options: &[u8], payload: &[u8]) -> Vec<u8>;
-    fn update_seq(&mut self, seq: u32) {}
+    fn update_seq(&mut self, _seq: u32) {}
     // fn handle(&self, ip_packet: &[u8], f: &mut File, logging: &mut Logging, x: T) -> Result<usize>;
 }
 
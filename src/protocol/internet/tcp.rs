@@ -1,7 +1,8 @@
 use std::fmt::Display;
 use std::net::SocketAddr;
 use std::ops::Deref;
-use crate::protocol::internet::{Datagram, Packet, Protocol, PseudoHeader};
+use crate::protocol::internet::{checksum, Packet, Protocol, PseudoHeader, TCP};
+use crate::protocol::{need, ParseError};
 use crate::util::{bytes_to_u32, u32_to_bytes};
 
 /*
@@ -37,6 +38,7 @@ pub struct Tcp {
     len: usize,
 }
 
+#[allow(dead_code)]
 struct Header {
     pub src_port: [u8; 2],
     pub dst_port: [u8; 2],
@@ -50,6 +52,9 @@ struct Header {
     pub options: Vec<Option>,
 }
 
+// Option kind of the timestamps, echoed with our sequence number in replies
+const TIMESTAMP: u8 = 8;
+
 struct Option {
     pub kind: u8,
     pub length: u8,
@@ -57,11 +62,16 @@ struct Option {
 }
 
 impl Tcp {
-    pub fn new(bytes: &[u8], pseudo_header: PseudoHeader) -> Self {
+    pub fn new(bytes: &[u8], pseudo_header: PseudoHeader) -> Result<Self, ParseError> {
+        need(bytes, 20)?;
         let data_offset = (bytes[12] >> 4 & 0b1111) as usize;
         let data_begin_idx = data_offset * 4;
+        if data_begin_idx < 20 {
+            return Err(ParseError::DataOffset(data_begin_idx));
+        }
+        need(bytes, data_begin_idx)?;
 
-        let options_bytes = bytes[20..data_begin_idx].to_vec();
+        let options_bytes = &bytes[20..data_begin_idx];
         let mut options = Vec::new();
         let mut option_idx = 0usize;
         loop {
@@ -69,15 +79,20 @@ impl Tcp {
 
             let kind = options_bytes[option_idx];
             if kind == 1 || kind == 0 { // A No-Operation Option: This option code can be used between options
-                option_idx = option_idx + 1;
+                option_idx += 1;
                 options.push(Option { kind, length: 0, data: Vec::new() });
                 continue;
             };
 
-            let length = options_bytes[option_idx + 1];
-            let data = (options_bytes[option_idx + 2..option_idx + length as usize]).to_vec();
+            // Kind and length count themselves, a timestamp is always 10 bytes
+            let length = options_bytes.get(option_idx + 1).copied().unwrap_or(0);
+            let end = option_idx + length as usize;
+            if length < 2 || end > options_bytes.len() || (kind == TIMESTAMP && length != 10) {
+                return Err(ParseError::Option { kind, length });
+            }
+            let data = (options_bytes[option_idx + 2..end]).to_vec();
             options.push(Option { kind, length, data });
-            option_idx = option_idx + length as usize;
+            option_idx = end;
         };
 
         let header = Header {
@@ -95,12 +110,12 @@ impl Tcp {
 
         let payload = bytes[data_begin_idx..].to_vec();
 
-        Self {
+        Ok(Self {
             header,
             pseudo_header,
             payload,
             len: bytes.len(),
-        }
+        })
     }
 }
 
@@ -125,6 +140,10 @@ impl Packet for Tcp {
         FlagsType(self.header.flags)
     }
 
+    fn seq(&self) -> std::option::Option<u32> {
+        Some(bytes_to_u32(&self.header.seq_no))
+    }
+
     fn info(&self) -> String {
         let mut info = String::new();
         let header = &self.header;
@@ -145,7 +164,7 @@ impl Packet for Tcp {
         info.push_str("\t---<options>---\n");
         for option in &header.options {
             info.push_str(&format!("\tkind:{}, length:{}, data:{:?}\n", option.kind, option.length, option.data));
-            if option.kind == 8 {
+            if option.kind == TIMESTAMP {
                 info.push_str(&format!("\t\tTimestamp: TSVal({}), TSecr({})\n", bytes_to_u32(&option.data[0..4]), bytes_to_u32(&option.data[4..8])));
             };
         }
@@ -166,13 +185,14 @@ impl Packet for Tcp {
             seq_no
         };
 
+        // SYN and FIN occupy one sequence number each
         let ack_no: u32 = bytes_to_u32(&header.seq_no);
         let ack_no = if ack_no == 0 {
             1
-        } else if self.payload.len() > 0 {
-            ack_no + self.payload.len() as u32
+        } else if header.flags & (*SYN | *FIN) != 0 {
+            ack_no.wrapping_add(self.payload.len() as u32 + 1)
         } else {
-            ack_no + 1
+            ack_no.wrapping_add(self.payload.len() as u32)
         };
 
         pack.extend_from_slice(&header.dst_port);
@@ -189,7 +209,7 @@ impl Packet for Tcp {
             }
             pack.push(option.kind);
             pack.push(option.length);
-            if option.kind == 8 {
+            if option.kind == TIMESTAMP {
                 pack.extend_from_slice(&seq_no.to_be_bytes());
                 pack.extend_from_slice(&option.data[0..4]);
             } else {
@@ -202,16 +222,12 @@ impl Packet for Tcp {
         pack[12] = offset;
 
         // Add payload
-        pack.extend_from_slice(&payload);
+        pack.extend_from_slice(payload);
 
         // Set header checksum
-        let mut header = self.pseudo_header.to_be_bytes();
-        let length = (pack.len() as u16).to_be_bytes();
-        (header[10], header[11]) = (length[0], length[1]); // Set length
-        header.extend_from_slice(&pack);
-        if header.len() % 2 != 0 { header.push(0) }
-        let checksum = Datagram::calc_checksum(&header);
-        (pack[16], pack[17]) = (checksum[0], checksum[1]);
+        let pseudo_header = &self.pseudo_header;
+        let checksum = checksum::transport(&pseudo_header.src_ip, &pseudo_header.dst_ip, TCP, &pack);
+        pack[16..18].copy_from_slice(&checksum.to_be_bytes());
 
         pack
     }
@@ -221,6 +237,26 @@ impl Packet for Tcp {
     }
 }
 
+// Sequence number of a packed segment
+pub fn seq(segment: &[u8]) -> u32 {
+    u32::from_be_bytes([segment[4], segment[5], segment[6], segment[7]])
+}
+
+// Renumber a packed segment, its checksum updated to match
+pub fn set_seq(segment: &mut [u8], seq: u32) {
+    let sum = checksum::update_u32(u16::from_be_bytes([segment[16], segment[17]]), self::seq(segment), seq);
+    segment[4..8].copy_from_slice(&seq.to_be_bytes());
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+}
+
+// Acknowledge up to `ack` in a packed segment, its checksum updated to match
+pub fn set_ack(segment: &mut [u8], ack: u32) {
+    let old = u32::from_be_bytes([segment[8], segment[9], segment[10], segment[11]]);
+    let sum = checksum::update_u32(u16::from_be_bytes([segment[16], segment[17]]), old, ack);
+    segment[8..12].copy_from_slice(&ack.to_be_bytes());
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+}
+
 #[derive(PartialEq, Eq)]
 pub struct FlagsType(pub u8);
 
@@ -270,6 +306,6 @@ impl FlagsType {
 
 impl Display for FlagsType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}({:08b})", self.info().to_string(), self.0)
+        write!(f, "{}({:08b})", self.info(), self.0)
     }
 }
\ No newline at end of file
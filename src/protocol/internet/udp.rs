@@ -1,5 +1,6 @@
-use std::net::SocketAddr;
-use crate::protocol::internet::{Datagram, Packet, Protocol, PseudoHeader};
+use std::net::{SocketAddr, SocketAddrV4};
+use crate::protocol::internet::{checksum, Packet, Protocol, PseudoHeader, UDP};
+use crate::protocol::{need, ParseError};
 use crate::util::bytes_to_u32;
 
 /*
@@ -24,6 +25,7 @@ pub struct Udp {
     payload: Vec<u8>,
 }
 
+#[allow(dead_code)]
 struct Header {
     pub src_port: [u8; 2],
     pub dst_port: [u8; 2],
@@ -32,8 +34,14 @@ struct Header {
 }
 
 impl Udp {
-    pub fn new(bytes: &[u8], pseudo_header: PseudoHeader) -> Self {
-        Self {
+    // The payload ends where the length field says
+    pub fn new(bytes: &[u8], pseudo_header: PseudoHeader) -> Result<Self, ParseError> {
+        need(bytes, 8)?;
+        let length = u16::from_be_bytes([bytes[4], bytes[5]]) as usize;
+        if length < 8 || length > bytes.len() {
+            return Err(ParseError::UdpLength { length, len: bytes.len() });
+        }
+        Ok(Self {
             header: Header {
                 src_port: [bytes[0], bytes[1]],
                 dst_port: [bytes[2], bytes[3]],
@@ -41,8 +49,8 @@ impl Udp {
                 checksum: [bytes[6], bytes[7]],
             },
             pseudo_header,
-            payload: bytes[8..bytes.len()].to_vec(),
-        }
+            payload: bytes[8..length].to_vec(),
+        })
     }
 }
 
@@ -71,24 +79,30 @@ impl Packet for Udp {
     }
 
     fn pack(&self, _: &[u8], payload: &[u8]) -> Vec<u8> {
-        let mut packet = Vec::new();
-        packet.extend_from_slice(&self.header.dst_port);
-        packet.extend_from_slice(&self.header.src_port);
+        let src = SocketAddrV4::new(self.pseudo_header.dst_ip.into(), u16::from_be_bytes(self.header.dst_port));
+        let dst = SocketAddrV4::new(self.pseudo_header.src_ip.into(), u16::from_be_bytes(self.header.src_port));
+        pack(src, dst, payload)
+    }
+}
 
-        let length = ((8 + payload.len()) as u16).to_be_bytes();
-        packet.extend_from_slice(&length);
-        packet.extend_from_slice(&[0, 0]); // checksum
+// A datagram from `src` to `dst`, the checksum covers both addresses
+pub fn pack(src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    packet.extend_from_slice(&src.port().to_be_bytes());
+    packet.extend_from_slice(&dst.port().to_be_bytes());
 
-        packet.extend_from_slice(&payload);
+    let length = ((8 + payload.len()) as u16).to_be_bytes();
+    packet.extend_from_slice(&length);
+    packet.extend_from_slice(&[0, 0]); // checksum
 
-        // Checksum
-        let mut header = self.pseudo_header.to_be_bytes();
-        (header[10], header[11]) = (length[0], length[1]); // Set length
-        header.extend_from_slice(&packet[..packet.len()]);
-        if header.len() % 2 != 0 { header.push(0); }
-        let checksum = Datagram::calc_checksum(&header);
-        (packet[6], packet[7]) = (checksum[0], checksum[1]);
+    packet.extend_from_slice(payload);
 
-        packet
-    }
+    // Checksum, all zeros is sent as all ones (RFC 768)
+    let checksum = match checksum::transport(&src.ip().octets(), &dst.ip().octets(), UDP, &packet) {
+        0 => 0xFFFF,
+        checksum => checksum,
+    };
+    packet[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
 }
\ No newline at end of file
@@ -0,0 +1,220 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use crate::protocol::internet::{checksum, icmp, Datagram, ICMP, TCP, UDP};
+
+// Borrowed views of a packet in the read buffer: the fields are read in place, nothing is copied.
+// Every accessor stays within the slice the view was checked against.
+
+// An IPv4 packet, its header checked to fit
+#[derive(Debug, Copy, Clone)]
+pub struct Ipv4<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Ipv4<'a> {
+    // None unless `bytes` holds a version 4 header with all of its options
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        let first = *bytes.first()?;
+        let header_len = (first & 0x0F) as usize * 4;
+        if first >> 4 != 4 || header_len < 20 || bytes.len() < header_len {
+            return None;
+        }
+        Some(Self { bytes })
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn header_len(&self) -> usize {
+        (self.bytes[0] & 0x0F) as usize * 4
+    }
+
+    pub fn total_len(&self) -> usize {
+        u16::from_be_bytes([self.bytes[2], self.bytes[3]]) as usize
+    }
+
+    pub fn header(&self) -> &'a [u8] {
+        &self.bytes[..self.header_len()]
+    }
+
+    // More fragments follow, or this is not the first one
+    pub fn is_fragment(&self) -> bool {
+        u16::from_be_bytes([self.bytes[6], self.bytes[7]]) & 0x3FFF != 0
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.bytes[8]
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.bytes[9]
+    }
+
+    pub fn src(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.bytes[12], self.bytes[13], self.bytes[14], self.bytes[15])
+    }
+
+    pub fn dst(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.bytes[16], self.bytes[17], self.bytes[18], self.bytes[19])
+    }
+
+    // Whatever follows the header, as read: a super-packet may be longer than its total length says
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header_len()..]
+    }
+
+    // The payload up to the total length, without whatever trails it
+    fn segment(&self) -> &'a [u8] {
+        let end = self.total_len().clamp(self.header_len(), self.bytes.len());
+        &self.bytes[self.header_len()..end]
+    }
+
+    // Whether the header checksum matches and, with `transport`, the TCP, UDP or ICMP one of an
+    // unfragmented packet. A UDP checksum of zero means the sender computed none.
+    pub fn checksums_valid(&self, transport: bool) -> bool {
+        if !checksum::verify(self.header()) {
+            return false;
+        }
+        if !transport || self.is_fragment() {
+            return true;
+        }
+        let (src, dst, segment) = (&self.bytes[12..16], &self.bytes[16..20], self.segment());
+        match self.protocol() {
+            TCP => checksum::verify_transport(src, dst, TCP, segment),
+            UDP => segment.get(6..8) == Some(&[0, 0]) || checksum::verify_transport(src, dst, UDP, segment),
+            ICMP => checksum::verify(segment),
+            _ => true,
+        }
+    }
+
+    pub fn tcp(&self) -> Option<Tcp<'a>> {
+        if self.protocol() != TCP {
+            return None;
+        }
+        Tcp::new(self.payload())
+    }
+
+    pub fn udp(&self) -> Option<Udp<'a>> {
+        if self.protocol() != UDP {
+            return None;
+        }
+        Udp::new(self.payload())
+    }
+
+    // The flow the packet belongs to, None for a TCP or UDP header cut short or a bad UDP length
+    pub fn key(&self) -> Option<FlowKey> {
+        let (src_port, dst_port) = match self.protocol() {
+            TCP => self.tcp().map(|tcp| (tcp.src_port(), tcp.dst_port()))?,
+            UDP => self.udp().map(|udp| (udp.src_port(), udp.dst_port()))?,
+            ICMP => (icmp::echo_request(self.payload()).map_or(0, |(id, _)| id), 0),
+            _ => (0, 0),
+        };
+        Some(FlowKey {
+            protocol: self.protocol(),
+            src: SocketAddr::new(IpAddr::V4(self.src()), src_port),
+            dst: SocketAddr::new(IpAddr::V4(self.dst()), dst_port),
+        })
+    }
+}
+
+// A TCP segment, its header and options checked to fit
+#[derive(Debug, Copy, Clone)]
+pub struct Tcp<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Tcp<'a> {
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        let header_len = (*bytes.get(12)? >> 4) as usize * 4;
+        if header_len < 20 || bytes.len() < header_len {
+            return None;
+        }
+        Some(Self { bytes })
+    }
+
+    pub fn src_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[2], self.bytes[3]])
+    }
+
+    pub fn seq(&self) -> u32 {
+        u32::from_be_bytes([self.bytes[4], self.bytes[5], self.bytes[6], self.bytes[7]])
+    }
+
+    pub fn ack(&self) -> u32 {
+        u32::from_be_bytes([self.bytes[8], self.bytes[9], self.bytes[10], self.bytes[11]])
+    }
+
+    pub fn header_len(&self) -> usize {
+        (self.bytes[12] >> 4) as usize * 4
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.bytes[13]
+    }
+
+    pub fn window(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[14], self.bytes[15]])
+    }
+
+    pub fn options(&self) -> &'a [u8] {
+        &self.bytes[20..self.header_len()]
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[self.header_len()..]
+    }
+}
+
+// A UDP datagram, its header checked to fit and its length field to lie within the slice
+#[derive(Debug, Copy, Clone)]
+pub struct Udp<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Udp<'a> {
+    pub fn new(bytes: &'a [u8]) -> Option<Self> {
+        let length = u16::from_be_bytes([*bytes.get(4)?, *bytes.get(5)?]) as usize;
+        if length < 8 || length > bytes.len() {
+            return None;
+        }
+        Some(Self { bytes })
+    }
+
+    pub fn src_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[2], self.bytes[3]])
+    }
+
+    pub fn length(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[4], self.bytes[5]])
+    }
+
+    // Up to the length field, without whatever trails it
+    pub fn payload(&self) -> &'a [u8] {
+        &self.bytes[8..self.length() as usize]
+    }
+}
+
+/// The 5-tuple a flow is looked up by, cheap to hash and copy.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub protocol: u8,
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+// Same as `Datagram::name`, e.g. TCP[10.0.0.2:50000]=>[1.1.1.1:443]
+impl fmt::Display for FlowKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}[{}]=>[{}]", Datagram::get_protocol(self.protocol), self.src, self.dst)
+    }
+}
@@ -1,2 +1,6 @@
 pub mod internet;
-pub mod socks5;
\ No newline at end of file
+pub mod socks5;
+mod error;
+
+pub use error::ParseError;
+pub(crate) use error::need;
@@ -0,0 +1,67 @@
+use crate::protocol::{need, ParseError};
+
+/*
+Username/password authentication (RFC 1929), once the server selected method X'02'.
+
+The client sends a username/password request:
+
+        +----+------+----------+------+----------+
+        |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+        +----+------+----------+------+----------+
+        | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+        +----+------+----------+------+----------+
+
+The VER field contains the current version of the subnegotiation, which is X'01'.
+ */
+pub struct Request {
+    ver: u8,
+    uname: Vec<u8>,
+    passwd: Vec<u8>,
+}
+
+impl Request {
+    // Longer names and passwords are cut to the 255 bytes the message allows
+    pub fn new(uname: &str, passwd: &str) -> Self {
+        let field = |s: &str| s.as_bytes()[..s.len().min(255)].to_vec();
+        Request {
+            ver: 1,
+            uname: field(uname),
+            passwd: field(passwd),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.ver, self.uname.len() as u8];
+        bytes.extend(&self.uname);
+        bytes.push(self.passwd.len() as u8);
+        bytes.extend(&self.passwd);
+        bytes
+    }
+}
+
+/*
+The server verifies the supplied UNAME and PASSWD, and sends the following response:
+
+        +----+--------+
+        |VER | STATUS |
+        +----+--------+
+        | 1  |   1    |
+        +----+--------+
+
+A STATUS field of X'00' indicates success. If the server returns a `failure' (STATUS value other
+than X'00') status, it MUST close the connection.
+ */
+pub struct Reply {
+    pub ver: u8,
+    pub status: u8,
+}
+
+impl Reply {
+    pub fn new(data: &[u8]) -> Result<Self, ParseError> {
+        need(data, 2)?;
+        Ok(Reply {
+            ver: data[0],
+            status: data[1],
+        })
+    }
+}
@@ -1,4 +1,5 @@
 pub mod negotiation;
+pub mod auth;
 pub mod request;
 
 
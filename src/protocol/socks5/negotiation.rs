@@ -1,3 +1,5 @@
+use crate::protocol::{need, ParseError};
+
 /**
 If the connection request succeeds, the client enters a negotiation for the authentication
 method to be used, authenticates with the chosen method, then sends a relay request.
@@ -47,11 +49,12 @@ pub struct Reply {
 }
 
 impl Reply {
-    pub fn new(data: &[u8]) -> Self {
-        Reply {
+    pub fn new(data: &[u8]) -> Result<Self, ParseError> {
+        need(data, 2)?;
+        Ok(Reply {
             ver: data[0],
             method: data[1],
-        }
+        })
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
@@ -1,3 +1,5 @@
+use crate::protocol::{need, ParseError};
+
 /*
 The SOCKS request/reply is formed as follows:
 
@@ -68,30 +70,39 @@ impl TcpMessage {
         }
     }
 
-    pub fn parse_reply(data: &[u8]) -> Self {
-        let len = data.len();
+    // The reply ends with the port after the address, its length given by the address type
+    pub fn parse_reply(data: &[u8]) -> Result<Self, ParseError> {
+        need(data, 5)?;
+        let len = Self::reply_len(data[3], data[4])?;
+        need(data, len)?;
         let ver = data[0];
         let opt = data[1];
         let rsv = data[2];
         let atyp = data[3];
         let addr = data[4..len - 2].to_vec();
         let port = [data[len - 2], data[len - 1]];
-        TcpMessage {
+        Ok(TcpMessage {
             ver,
             opt,
             rsv,
             atyp,
             addr,
             port,
+        })
+    }
+
+    // Length of a reply with address type `atyp`, `first` the first byte of its address
+    pub fn reply_len(atyp: u8, first: u8) -> Result<usize, ParseError> {
+        match atyp {
+            1 => Ok(4 + 4 + 2),
+            3 => Ok(4 + 1 + first as usize + 2),
+            4 => Ok(4 + 16 + 2),
+            atyp => Err(ParseError::AddressType(atyp)),
         }
     }
 
     pub fn as_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        bytes.push(self.ver);
-        bytes.push(self.opt);
-        bytes.push(self.rsv);
-        bytes.push(self.atyp);
+        let mut bytes = vec![self.ver, self.opt, self.rsv, self.atyp];
         bytes.extend(&self.addr);
         bytes.extend(&self.port);
         bytes
@@ -119,6 +130,7 @@ The fields in the UDP request header are:
           o  DATA    user data
 
  */
+#[allow(dead_code)]
 pub struct UdpMessage {
     rsv: [u8; 2],
     frag: u8,
@@ -0,0 +1,200 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Traffic counters of one tun2socks instance, updated by the dispatcher and readable from any thread.
+/// "Up" is from the clients behind the interface towards the remotes, "down" the other way.
+#[derive(Debug, Default)]
+pub struct Stats {
+    packets_up: AtomicU64,
+    packets_down: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    tcp_flows: AtomicU64,
+    udp_flows: AtomicU64,
+    icmp_flows: AtomicU64,
+    connect_attempts: AtomicU64,
+    connect_failures: AtomicU64,
+    dns_queries: AtomicU64,
+    evictions: AtomicU64,
+    idle_timeouts: AtomicU64,
+    drops: [AtomicU64; DROP_REASONS],
+    dns_cache_hits: AtomicU64,
+    dns_cache_misses: AtomicU64,
+    dns_cache_entries: AtomicU64,
+}
+
+/// Why a packet from the interface was dropped, or a reply to it could not be sent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DropReason {
+    /// Too short or otherwise not a valid IP packet.
+    Malformed,
+    /// Not IPv4.
+    UnsupportedVersion,
+    /// Neither TCP nor UDP.
+    UnsupportedProtocol,
+    /// TCP segment other than a SYN without a flow.
+    NoFlow,
+    /// New flow refused by the overflow policy.
+    FlowTableFull,
+    /// Writing to the interface failed.
+    WriteError,
+    /// An IP, TCP, UDP or ICMP checksum did not match, see `Config::verify_checksums`.
+    BadChecksum,
+    /// Time to live ran out at the interface, answered with ICMP time exceeded.
+    TtlExceeded,
+}
+
+const DROP_REASONS: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl Protocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+            Protocol::Icmp => "icmp",
+        }
+    }
+}
+
+/// Copy of the counters at one point in time.
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// IP packets read from the interface.
+    pub packets_up: u64,
+    /// IP packets written to the interface.
+    pub packets_down: u64,
+    /// TCP and UDP payload sent to remotes.
+    pub bytes_up: u64,
+    /// TCP and UDP payload received from remotes.
+    pub bytes_down: u64,
+    /// Currently open flows per protocol.
+    pub tcp_flows: u64,
+    pub udp_flows: u64,
+    pub icmp_flows: u64,
+    /// Upstream connections (TCP) and sessions (UDP) opened, and those that failed to.
+    pub connect_attempts: u64,
+    pub connect_failures: u64,
+    /// UDP datagrams to port 53.
+    pub dns_queries: u64,
+    /// Flows closed to make room for new ones.
+    pub evictions: u64,
+    /// Flows closed by their idle timeout.
+    pub idle_timeouts: u64,
+    /// Dropped packets by `DropReason`.
+    pub drops_malformed: u64,
+    pub drops_unsupported_version: u64,
+    pub drops_unsupported_protocol: u64,
+    pub drops_no_flow: u64,
+    pub drops_flow_table_full: u64,
+    pub drops_write_error: u64,
+    pub drops_bad_checksum: u64,
+    pub drops_ttl_exceeded: u64,
+    /// New flows whose destination was named by an earlier DNS response, and those that were not.
+    pub dns_cache_hits: u64,
+    pub dns_cache_misses: u64,
+    /// Addresses currently in the DNS cache.
+    pub dns_cache_entries: u64,
+}
+
+impl Stats {
+    pub fn packet_up(&self) {
+        self.packets_up.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_down(&self) {
+        self.packets_down.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_up(&self, n: usize) {
+        self.bytes_up.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn bytes_down(&self, n: usize) {
+        self.bytes_down.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn flow_opened(&self, protocol: Protocol) {
+        self.flows(protocol).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn flow_closed(&self, protocol: Protocol) {
+        self.flows(protocol).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_attempt(&self) {
+        self.connect_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_failure(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dns_query(&self) {
+        self.dns_queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn eviction(&self) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn idle_timeout(&self) {
+        self.idle_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, reason: DropReason) {
+        self.drops[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dns_cache_lookup(&self, hit: bool) {
+        let counter = if hit { &self.dns_cache_hits } else { &self.dns_cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dns_cache_entries(&self, n: usize) {
+        self.dns_cache_entries.store(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Snapshot {
+            packets_up: get(&self.packets_up),
+            packets_down: get(&self.packets_down),
+            bytes_up: get(&self.bytes_up),
+            bytes_down: get(&self.bytes_down),
+            tcp_flows: get(&self.tcp_flows),
+            udp_flows: get(&self.udp_flows),
+            icmp_flows: get(&self.icmp_flows),
+            connect_attempts: get(&self.connect_attempts),
+            connect_failures: get(&self.connect_failures),
+            dns_queries: get(&self.dns_queries),
+            evictions: get(&self.evictions),
+            idle_timeouts: get(&self.idle_timeouts),
+            drops_malformed: get(&self.drops[DropReason::Malformed as usize]),
+            drops_unsupported_version: get(&self.drops[DropReason::UnsupportedVersion as usize]),
+            drops_unsupported_protocol: get(&self.drops[DropReason::UnsupportedProtocol as usize]),
+            drops_no_flow: get(&self.drops[DropReason::NoFlow as usize]),
+            drops_flow_table_full: get(&self.drops[DropReason::FlowTableFull as usize]),
+            drops_write_error: get(&self.drops[DropReason::WriteError as usize]),
+            drops_bad_checksum: get(&self.drops[DropReason::BadChecksum as usize]),
+            drops_ttl_exceeded: get(&self.drops[DropReason::TtlExceeded as usize]),
+            dns_cache_hits: get(&self.dns_cache_hits),
+            dns_cache_misses: get(&self.dns_cache_misses),
+            dns_cache_entries: get(&self.dns_cache_entries),
+        }
+    }
+
+    fn flows(&self, protocol: Protocol) -> &AtomicU64 {
+        match protocol {
+            Protocol::Tcp => &self.tcp_flows,
+            Protocol::Udp => &self.udp_flows,
+            Protocol::Icmp => &self.icmp_flows,
+        }
+    }
+}
@@ -1,91 +1,235 @@
-use std::ffi::{c_char, CStr};
 use std::fs::File;
-use std::io::{Error, ErrorKind, Read};
-use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::io::{Error, ErrorKind, Result};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::raw::c_int;
-use std::sync::{Arc, mpsc};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::logging::Logging;
-use crate::protocol::internet::Datagram;
-use crate::thread_pool::ThreadPool;
+use crate::capture::Capture;
+use crate::config::Config;
+use crate::dispatcher::{Dispatcher, Inspector, Stopper};
+use crate::dispatcher::flow::FlowInfo;
+use crate::logging::{log, Logging};
+#[cfg(feature = "metrics")]
+use crate::metrics::Exporter;
+use crate::stats::{Snapshot, Stats};
 
-const MTU: usize = 1500;
+/// A tun2socks instance running on its own threads, one dispatcher per queue of the interface.
+pub struct Handle {
+    stoppers: Vec<Stopper>,
+    stats: Arc<Stats>,
+    inspectors: Vec<Inspector>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Exporter>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
 
-pub fn main(fd: c_int, log_path: *const c_char) {
-    let raw_fd = RawFd::from(fd).as_raw_fd();
-    let mut interface = unsafe { File::from_raw_fd(raw_fd) };
+impl Handle {
+    /// Reset open TCP flows, close every upstream socket and join the dispatcher threads.
+    /// Fails with `TimedOut` when a dispatcher is still busy after `deadline`, its thread is then left
+    /// to end by itself.
+    pub fn stop(mut self, deadline: Duration) -> Result<()> {
+        let start = Instant::now();
+        // Every queue is told first, so that they all wind down within the one deadline
+        let mut result = Ok(());
+        for stopper in &self.stoppers {
+            result = result.and(stopper.signal());
+        }
+        for stopper in &self.stoppers {
+            result = result.and(stopper.wait(deadline.saturating_sub(start.elapsed())));
+        }
+        match result {
+            Ok(()) => self.join(),
+            Err(_) => self.detach(),
+        }
+        result
+    }
 
-    let c_str = unsafe { CStr::from_ptr(log_path) };
-    let logging_path = c_str.to_string_lossy().into_owned();
-    let mut logging = Logging::new(&logging_path);
-    logging.i(format!("Hello tun2socks main, fd({fd}), logging_path({logging_path})"));
+    /// Wait until the instance ends by itself, i.e. the interface is closed.
+    pub fn join(&mut self) {
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap_or(());
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics.take() {
+            metrics.stop();
+        }
+    }
 
-    let (reporter, events) = mpsc::channel();
-    let reporter = Arc::new(reporter);
-    let pool = ThreadPool::new(10, Arc::clone(&reporter));
+    // Let go of the dispatcher threads without waiting for them
+    fn detach(&mut self) {
+        self.threads.clear();
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics.take() {
+            metrics.stop();
+        }
+    }
 
-    let mut cloned_interface = interface.try_clone().unwrap();
-    let mut cloned_logging = logging.clone();
-    thread::spawn(move || {
-        ThreadPool::run(&mut cloned_interface, &mut cloned_logging, events);
-    });
+    // Whether `other` is a handle of this instance, e.g. its `detached` one
+    pub(crate) fn same_instance(&self, other: &Handle) -> bool {
+        Arc::ptr_eq(&self.stats, &other.stats)
+    }
 
-    let mut buf = vec![0; MTU]; // Usual internet header length
-    let mut last_err = Error::new(ErrorKind::InvalidInput, "Oh no");
+    // Handle stopping the instance without owning its thread
+    pub fn detached(&self) -> Self {
+        Self {
+            stoppers: self.stoppers.clone(),
+            stats: Arc::clone(&self.stats),
+            inspectors: self.inspectors.clone(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            threads: Vec::new(),
+        }
+    }
 
-    loop {
-        match interface.read(&mut buf) {
-            Ok(0) => {
-                logging.i("reach end".to_string());
-                break;
-            }
-            Ok(n) => {
-                let bytes = &buf[..n];
+    /// Traffic counters so far, cheap enough to poll.
+    pub fn stats(&self) -> Snapshot {
+        self.stats.snapshot()
+    }
 
-                #[cfg(any(target_os = "macos", target_os = "ios"))]
-                    let bytes = &buf[4..n];
+    /// Every open flow, asked from the dispatcher threads.
+    /// Fails with `TimedOut` when one does not answer within `deadline`, `NotConnected` once they ended.
+    pub fn flows(&self, deadline: Duration) -> Result<Vec<FlowInfo>> {
+        let start = Instant::now();
+        let mut flows = Vec::new();
+        for inspector in &self.inspectors {
+            flows.extend(inspector.flows(deadline.saturating_sub(start.elapsed()))?);
+        }
+        flows.sort_by_key(|flow| flow.id);
+        Ok(flows)
+    }
 
-                if n < 20 {
-                    logging.e(format!("error internet datagram(len[{n}]): {:?}", bytes));
-                    continue;
-                }
-                logging.i(format!("--->> Recv: len({})\n{:?}", n, bytes));
+    /// Address the metrics are served on, if `Config::metrics_port` was set.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_addr(&self) -> Option<std::net::SocketAddr> {
+        self.metrics.as_ref().map(Exporter::addr)
+    }
+}
 
-                let version = (bytes[0] >> 4) & 0b1111;
-                if version != 4 {
-                    logging.w(format!("Unsupported version {version}"));
-                    continue;
-                };
+/// Start tun2socks on the TUN `fd` in a new thread.
+/// Without `logging_path` lines only go to the log callback.
+/// On success the fd is owned by tun2socks and closed once it stops, on error it is left open.
+pub fn start(fd: c_int, logging_path: Option<&str>, config: Config) -> Result<Handle> {
+    start_queues(&[fd], logging_path, config)
+}
 
-                let datagram = Datagram::new(&bytes);
-                ThreadPool::execute(datagram, &mut logging);
+/// Start tun2socks on the queues of a multiqueue TUN device, each served by its own thread.
+/// The flow table is split between them. The kernel steers packets to a queue by their 5-tuple, so
+/// a UDP client reaching several remotes spans queues and its mapping is only full cone with one queue.
+pub fn start_queues(fds: &[c_int], logging_path: Option<&str>, config: Config) -> Result<Handle> {
+    if fds.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "no fd"));
+    }
+    if let Some(fd) = fds.iter().find(|fd| **fd < 0) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("invalid fd({fd})")));
+    }
+    let logging = match logging_path {
+        Some(path) => match Logging::with_rotation(path, config.log_rotation) {
+            Ok(logging) => logging,
+            Err(err) => return Err(Error::new(err.kind(), format!("open {path}: {err}"))),
+        },
+        None => Logging::without_file(),
+    };
+    let capture = config.capture.as_ref().map(Capture::open).transpose()?.map(|capture| Arc::new(Mutex::new(capture)));
+    log!(logging, Info, Tun, "start, fds({fds:?}), logging_path({logging_path:?}), config({config:?})");
+
+    let stats = Arc::new(Stats::default());
+    let mut dispatchers = Vec::with_capacity(fds.len());
+    for (queue, fd) in fds.iter().enumerate() {
+        let raw_fd = RawFd::from(*fd).as_raw_fd();
+        let interface = unsafe { File::from_raw_fd(raw_fd) };
+        let mut dispatcher = match Dispatcher::new(interface, logging.clone(), &config) {
+            Ok(dispatcher) => dispatcher,
+            Err(err) => {
+                log!(logging, Error, Tun, "dispatcher error: {err}");
+                release(dispatchers);
+                return Err(Error::new(err.kind(), format!("set up interface fd({fd}): {err}")));
             }
+        };
+        dispatcher.set_stats(Arc::clone(&stats));
+        dispatcher.set_queue(queue, fds.len());
+        if let Some(capture) = &capture {
+            dispatcher.set_capture(Arc::clone(capture));
+        }
+        dispatchers.push(dispatcher);
+    }
+    let stoppers = dispatchers.iter().map(Dispatcher::stopper).collect();
+    let inspectors = dispatchers.iter().map(Dispatcher::inspector).collect();
+    #[cfg(feature = "metrics")]
+    let metrics = match config.metrics_port {
+        Some(port) => match Exporter::start(port, Arc::clone(&stats), config.max_flows.max(1), logging.clone()) {
+            Ok(metrics) => Some(metrics),
             Err(err) => {
-                match err.kind() {
-                    ErrorKind::WouldBlock => {}
-                    ErrorKind::InvalidInput => {
-                        logging.i("tun read error InvalidInput".to_string());
-                        break;
-                    }
-                    ErrorKind::Other => {
-                        logging.e(format!("tun read error other: {:#?}", err));
-                    }
-                    _ => {
-                        logging.e(format!("tun read error _: {:#?}", err));
-                        break;
+                release(dispatchers);
+                return Err(Error::new(err.kind(), format!("serve metrics on port {port}: {err}")));
+            }
+        },
+        None => None,
+    };
+
+    // Every thread is built before any dispatcher starts, so a failed spawn leaves none running
+    let mut threads = Vec::with_capacity(dispatchers.len());
+    let mut starts = Vec::with_capacity(dispatchers.len());
+    let single = dispatchers.len() == 1;
+    for queue in 0..dispatchers.len() {
+        let name = match single {
+            true => "tun2socks".to_string(),
+            false => format!("tun2socks-{queue}"),
+        };
+        let (start, started) = mpsc::channel::<Dispatcher>();
+        let thread_logging = logging.clone();
+        let spawned = thread::Builder::new()
+            .name(name)
+            .spawn(move || {
+                if let Ok(mut dispatcher) = started.recv() {
+                    if let Err(err) = dispatcher.run() {
+                        log!(thread_logging, Error, Tun, "dispatcher run error: {err}");
                     }
                 }
-
-                if err.kind() != last_err.kind() {
-                    last_err = err;
-                    logging.i(format!("tun read error, kind: {}, err: {}", last_err.kind(), last_err));
-                };
+            });
+        match spawned {
+            Ok(thread) => threads.push(thread),
+            Err(err) => {
+                log!(logging, Error, Tun, "spawn queue {queue}: {err}");
+                // The threads so far end as soon as their start is dropped
+                drop(starts);
+                for thread in threads {
+                    thread.join().unwrap_or(());
+                }
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = metrics {
+                    metrics.stop();
+                }
+                release(dispatchers);
+                return Err(err);
             }
         }
+        starts.push(start);
+    }
+    for (start, dispatcher) in starts.into_iter().zip(dispatchers) {
+        start.send(dispatcher).unwrap_or(());
+    }
+
+    Ok(Handle {
+        stoppers,
+        stats,
+        inspectors,
+        #[cfg(feature = "metrics")]
+        metrics,
+        threads,
+    })
+}
+
+// A failed start leaves the fds open, they are still the caller's
+fn release(dispatchers: Vec<Dispatcher>) {
+    for dispatcher in dispatchers {
+        let _ = dispatcher.into_interface().into_raw_fd();
     }
+}
 
-    ThreadPool::stop();
-    drop(interface);
-    drop(reporter);
-}
\ No newline at end of file
+/// Run tun2socks on the TUN `fd` until the interface is closed.
+pub fn main(fd: c_int, logging_path: Option<&str>, config: Config) -> Result<()> {
+    start(fd, logging_path, config)?.join();
+    Ok(())
+}
@@ -1,9 +1,11 @@
+pub mod pool;
+
 pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
     let mut result = 0u32;
     let mut mv = bytes.len();
     for byte in bytes {
-        mv = mv - 1;
-        result = result + (((*byte) as u32) << mv * 8);
+        mv -= 1;
+        result += ((*byte) as u32) << (mv * 8);
     };
     result
 }
@@ -12,12 +14,12 @@ pub fn bytes_to_u32_no_prefix(bytes: &[u8], n_prefix: u32) -> u32 {
     let mut result = 0u32;
     let mut mv = bytes.len();
     for byte in bytes {
-        mv = mv - 1;
+        mv -= 1;
         if mv == bytes.len() - 1 {
             let highest = byte & (2u8.pow(8 - n_prefix) - 1);
-            result = (highest as u32) << mv * 8;
+            result = (highest as u32) << (mv * 8);
         } else {
-            result = result + ((*byte as u32) << mv * 8);
+            result += (*byte as u32) << (mv * 8);
         }
     };
     result
@@ -42,4 +44,38 @@ pub fn u32_to_bytes(number: u32) -> [u8; 4] {
     let fourth = (number & 0xFF) as u8;
 
     [first, second, third, fourth]
-}
\ No newline at end of file
+}
+
+// Append `s` to `out` as a quoted JSON string
+pub fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// Decode %XX escapes as in the userinfo of a URL, `None` for a broken escape or invalid UTF-8
+pub fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,29 @@
+// Buffers of one size taken and given back, so a warm packet path allocates nothing
+pub struct Pool {
+    size: usize,
+    free: Vec<Vec<u8>>,
+    // Free buffers kept at most, the others are dropped when given back
+    capacity: usize,
+}
+
+impl Pool {
+    pub fn new(size: usize, capacity: usize) -> Self {
+        Self { size, free: Vec::with_capacity(capacity), capacity }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // A buffer of `size` bytes, its content left from its last use
+    pub fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_else(|| vec![0; self.size])
+    }
+
+    // Buffers of another size are not the pool's, they are dropped
+    pub fn give(&mut self, buf: Vec<u8>) {
+        if buf.len() == self.size && self.free.len() < self.capacity {
+            self.free.push(buf);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+// Build tests/c/api_test.c against the static library and run it
+#[test]
+fn c_api() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // target/<profile>/deps/c_api-<hash>
+    let target_dir = std::env::current_exe().unwrap().parent().unwrap().parent().unwrap().to_path_buf();
+    let build_dir = manifest_dir.join("build");
+    std::fs::create_dir_all(&build_dir).unwrap();
+    let program = build_dir.join("api_test");
+
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg(manifest_dir.join("tests/c/api_test.c"))
+        .arg(target_dir.join("libtun2socks_rust.a"))
+        .args(["-lpthread", "-ldl", "-lm", "-o"])
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = Command::new(&program).arg(build_dir.join("api_test.log")).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,63 @@
+#![cfg(feature = "metrics")]
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::fd::FromRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tun2socks_rust::config::Config;
+use tun2socks_rust::tun;
+
+// Plain HTTP/1.1 request, the whole response
+fn get(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+// Scrape /metrics served for a socketpair standing in for the TUN device
+#[test]
+fn metrics_endpoint() {
+    let mut fds = [0; 2];
+    let rs = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+    assert_eq!(rs, 0);
+    let mut client = unsafe { File::from_raw_fd(fds[1]) };
+
+    let config = Config { max_flows: 64, metrics_port: Some(0), ..Config::default() };
+    let handle = tun::start(fds[0], None, config).unwrap();
+    let addr = handle.metrics_addr().unwrap();
+    assert!(addr.ip().is_loopback());
+
+    // Too short for an IP header
+    client.write_all(&[0x45, 0]).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let response = loop {
+        let response = get(addr, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        if response.contains("tun2socks_drops_total{reason=\"malformed\"} 1\n") || Instant::now() > deadline {
+            break response;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+    assert!(response.contains("tun2socks_drops_total{reason=\"malformed\"} 1\n"), "{response}");
+    assert!(response.contains("# TYPE tun2socks_packets_total counter\n"));
+    assert!(response.contains("tun2socks_packets_total{direction=\"up\"} 1\n"));
+    assert!(response.contains("tun2socks_flows{protocol=\"tcp\"} 0\n"));
+    assert!(response.contains("tun2socks_max_flows 64\n"));
+    assert!(response.contains("tun2socks_dns_cache_entries 0\n"));
+    if cfg!(target_os = "linux") {
+        assert!(response.contains("tun2socks_threads "));
+    }
+
+    assert!(get(addr, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(get(addr, "POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+    handle.stop(Duration::from_secs(1)).unwrap();
+    assert!(TcpStream::connect(addr).is_err());
+}